@@ -0,0 +1,280 @@
+use crate::data_types::joplin::{JoplinData, ResourceInfo};
+use crate::error::BridgeError;
+use base64::Engine;
+use regex::Regex;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AttachmentMode {
+    Export, // Copied next to the .fnx, linked with a relative path
+    Embed,  // data: URI in the note, up to the embed limit
+}
+
+#[derive(Debug, Clone)]
+pub struct AttachmentOptions {
+    pub mode: AttachmentMode,
+    pub embed_limit: u64, // Bigger attachments are exported even with Embed
+    pub fnx_dir: PathBuf, // Links are relative to the .fnx
+    pub dir_name: String, // "<fnx name>_attachments"
+}
+
+impl AttachmentOptions {
+    pub fn new(fnx_path: &str, mode: AttachmentMode, embed_limit: u64) -> AttachmentOptions {
+        let path = Path::new(fnx_path);
+        let stem = path
+            .file_stem()
+            .map(|x| x.to_string_lossy().to_string())
+            .unwrap_or_else(|| "FeatherNotes".to_string());
+        AttachmentOptions {
+            mode,
+            embed_limit,
+            fnx_dir: path.parent().map(|x| x.to_path_buf()).unwrap_or_default(),
+            dir_name: format!("{}_attachments", stem),
+        }
+    }
+
+    pub fn export_dir(&self) -> PathBuf {
+        self.fnx_dir.join(&self.dir_name)
+    }
+}
+
+impl Default for AttachmentOptions {
+    fn default() -> AttachmentOptions {
+        AttachmentOptions::new("FeatherNotes.fnx", AttachmentMode::Export, 1024 * 1024)
+    }
+}
+
+pub fn data_uri(mime: &str, bytes: &[u8]) -> String {
+    format!(
+        "data:{};base64,{}",
+        mime,
+        base64::engine::general_purpose::STANDARD.encode(bytes)
+    )
+}
+
+// Joplin images and attachments keep their resource id in the URI, so they aren't uploaded again
+pub fn resource_data_uri(resource: &ResourceInfo, bytes: &[u8]) -> String {
+    data_uri(
+        &format!("{};joplin-resource={}", resource.mime, resource.id),
+        bytes,
+    )
+}
+
+fn export_file(
+    file: &Path,
+    file_name: &str,
+    options: &AttachmentOptions,
+) -> Result<String, BridgeError> {
+    let dir = options.export_dir();
+    std::fs::create_dir_all(&dir)?;
+    let target = dir.join(file_name);
+    // The resource may have changed since it was exported, the cached file is newer then
+    let source = std::fs::metadata(file)?;
+    let up_to_date = match std::fs::metadata(&target) {
+        Ok(exported) => {
+            exported.len() == source.len() && exported.modified().ok() >= source.modified().ok()
+        }
+        Err(_) => false,
+    };
+    if !up_to_date {
+        debug!("Exporting attachment to {}", target.display());
+        std::fs::copy(file, &target)?;
+    }
+    Ok(percent_encode(&format!(
+        "{}/{}",
+        options.dir_name, file_name
+    )))
+}
+
+// "My Notes_attachments/x.pdf" would end the markdown link at the space
+fn percent_encode(path: &str) -> String {
+    let mut out = String::new();
+    for byte in path.bytes() {
+        if byte.is_ascii_alphanumeric() || b"-._~/".contains(&byte) {
+            out.push(byte as char);
+        } else {
+            out.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    out
+}
+
+fn percent_decode(path: &str) -> String {
+    let bytes = path.as_bytes();
+    let mut out = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .and_then(|x| std::str::from_utf8(x).ok())
+            .and_then(|x| u8::from_str_radix(x, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(x)) => {
+                out.push(x);
+                i += 3;
+            }
+            (x, _) => {
+                out.push(x);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&out).to_string()
+}
+
+// [report.pdf](:/060a68f375f0417aad4a8f02e9f6572f) becomes a link to the exported file or a data URI.
+// Images are done before by fix_embedding_files_md so only links are left with :/
+pub fn fix_attachment_links_md(
+    md: String,
+    resources: &[ResourceInfo],
+    files: &HashMap<String, PathBuf>,
+    options: &AttachmentOptions,
+) -> Result<String, BridgeError> {
+    let re_links = Regex::new(r"\[([^\]]*)\]\(:/([0-9a-fA-F]{32})\)").unwrap();
+
+    let mut converted_md = md.clone();
+    for cap in re_links.captures_iter(&md) {
+        let (resource, file) = match (
+            resources.iter().find(|x| x.id == cap[2]),
+            files.get(&cap[2]),
+        ) {
+            (Some(resource), Some(file)) => (resource, file),
+            _ => {
+                warn!(
+                    "Attachment {} isn't attached to the note, leaving the link",
+                    &cap[2]
+                );
+                continue;
+            }
+        };
+
+        let size = std::fs::metadata(file)?.len();
+        let target = if options.mode == AttachmentMode::Embed && size <= options.embed_limit {
+            resource_data_uri(resource, &std::fs::read(file)?)
+        } else {
+            if options.mode == AttachmentMode::Embed {
+                info!(
+                    "{} is bigger than the embed limit, exporting it instead",
+                    resource.title
+                );
+            }
+            export_file(file, &resource.file_name(), options)?
+        };
+
+        debug!("Attachment {} is now at {:.80}", resource.title, target);
+        converted_md = converted_md.replace(&cap[0], &format!("[{}]({})", &cap[1], target));
+    }
+
+    Ok(converted_md)
+}
+
+fn extension_for_mime(mime: &str) -> &str {
+    match mime {
+        "image/jpeg" => "jpg",
+        "image/svg+xml" => "svg",
+        "application/pdf" => "pdf",
+        "text/plain" => "txt",
+        _ => mime.rsplit('/').next().unwrap_or("bin"),
+    }
+}
+
+pub fn mime_for_extension(extension: &str) -> &str {
+    match extension.to_lowercase().as_str() {
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "svg" => "image/svg+xml",
+        "webp" => "image/webp",
+        "bmp" => "image/bmp",
+        "pdf" => "application/pdf",
+        "txt" => "text/plain",
+        _ => "application/octet-stream",
+    }
+}
+
+// Only files in the attachments dir are uploaded, a link can point anywhere on the disk
+fn attachment_file(target: &str, options: &AttachmentOptions) -> Option<PathBuf> {
+    let file = options.fnx_dir.join(percent_decode(target));
+    if !file.is_file() {
+        return None;
+    }
+    match (file.canonicalize(), options.export_dir().canonicalize()) {
+        (Ok(file), Ok(dir)) if file.starts_with(&dir) => Some(file),
+        _ => {
+            warn!(
+                "{} isn't in {}, leaving the link and not uploading it",
+                target,
+                options.export_dir().display()
+            );
+            None
+        }
+    }
+}
+
+// The other way: data URIs and files next to the .fnx become joplin resources again.
+// Exported files keep their resource id as name and embedded ones have it in the URI,
+// those don't need uploading
+pub fn upload_attachments_md(
+    md: String,
+    joplin_struct: &JoplinData,
+    options: &AttachmentOptions,
+) -> Result<String, BridgeError> {
+    let re_links = Regex::new(r"(!?)\[([^\]]*)\]\(([^)\s]+)\)").unwrap();
+    let re_data =
+        Regex::new(r"^data:([^;,]+)(?:;joplin-resource=([0-9a-fA-F]{32}))?;base64,(.*)$").unwrap();
+    let re_id = Regex::new(r"^[0-9a-fA-F]{32}$").unwrap();
+
+    let mut converted_md = md.clone();
+    for cap in re_links.captures_iter(&md) {
+        let target = &cap[3];
+        let title = if cap[2].is_empty() {
+            "attachment"
+        } else {
+            &cap[2]
+        };
+
+        let resource_id = if let Some(data) = re_data.captures(target) {
+            match data.get(2) {
+                Some(id) => id.as_str().to_string(),
+                None => {
+                    let bytes = base64::engine::general_purpose::STANDARD.decode(&data[3])?;
+                    let file_name = format!("{}.{}", title, extension_for_mime(&data[1]));
+                    joplin_struct.upload_resource(title, &file_name, bytes)?
+                }
+            }
+        } else if target.contains("://")
+            || target.starts_with(":/")
+            || target.starts_with('#')
+            || target.starts_with("mailto:")
+        {
+            continue;
+        } else {
+            let file = match attachment_file(target, options) {
+                Some(x) => x,
+                None => continue,
+            };
+            let stem = file
+                .file_stem()
+                .map(|x| x.to_string_lossy().to_string())
+                .unwrap_or_default();
+            if re_id.is_match(&stem) {
+                stem
+            } else {
+                let file_name = file
+                    .file_name()
+                    .map(|x| x.to_string_lossy().to_string())
+                    .unwrap_or_default();
+                joplin_struct.upload_resource(title, &file_name, std::fs::read(&file)?)?
+            }
+        };
+
+        debug!("{:.80} is resource {}", target, resource_id);
+        converted_md = converted_md.replace(
+            &cap[0],
+            &format!("{}[{}](:/{})", &cap[1], &cap[2], resource_id),
+        );
+    }
+
+    Ok(converted_md)
+}
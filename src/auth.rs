@@ -0,0 +1,174 @@
+use crate::error::BridgeError;
+use serde_json::Value;
+use std::io::Write;
+use std::path::PathBuf;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+// $XDG_CONFIG_HOME/feather-joplin-bridge/token, or the same in ~/.config
+pub fn token_cache_path() -> Option<PathBuf> {
+    let config_dir = match std::env::var("XDG_CONFIG_HOME") {
+        Ok(x) if !x.is_empty() => PathBuf::from(x),
+        _ => match std::env::var("HOME") {
+            Ok(x) => PathBuf::from(x).join(".config"),
+            Err(_) => match std::env::var("APPDATA") {
+                Ok(x) => PathBuf::from(x),
+                Err(_) => return None,
+            },
+        },
+    };
+    Some(config_dir.join("feather-joplin-bridge").join("token"))
+}
+
+fn read_cached_token() -> Option<String> {
+    let path = token_cache_path()?;
+    let token = std::fs::read_to_string(&path).ok()?;
+    let token = token.trim().to_string();
+    if token.is_empty() {
+        return None;
+    }
+    debug!("Using cached token from {}", path.display());
+    Some(token)
+}
+
+// Only the user can read it
+fn write_cached_token(token: &str) -> Result<(), BridgeError> {
+    let path = match token_cache_path() {
+        Some(x) => x,
+        None => {
+            return Err(BridgeError::Auth(
+                "No config directory for the token".into(),
+            ))
+        }
+    };
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options.open(&path)?;
+    file.write_all(token.as_bytes())?;
+
+    #[cfg(unix)]
+    {
+        // mode() only applies to new files
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600))?;
+    }
+
+    info!("Token saved to {}", path.display());
+    Ok(())
+}
+
+// Joplin answers 403 for a token it doesn't know, anything else but success is Joplin's problem
+fn token_works(base_url: &str, token: &str) -> Result<bool, BridgeError> {
+    let resp = reqwest::blocking::get(format!("{}/folders?token={}&limit=1", base_url, token))?;
+    let status = resp.status();
+    if status == reqwest::StatusCode::FORBIDDEN || status == reqwest::StatusCode::UNAUTHORIZED {
+        return Ok(false);
+    }
+    if !status.is_success() {
+        return Err(BridgeError::Joplin(format!(
+            "Checking the cached token: {}",
+            status
+        )));
+    }
+    Ok(true)
+}
+
+// Nobody clicks accept during a cron run, it shouldn't wait forever for that
+const AUTH_WAIT: Duration = Duration::from_secs(180);
+
+// https://joplinapp.org/help/api/references/rest_api/#authorisation
+fn request_token(base_url: &str) -> Result<String, BridgeError> {
+    let client = reqwest::blocking::Client::new();
+    let resp = client.post(format!("{}/auth", base_url)).send()?;
+    let v: Value = serde_json::from_str(&resp.text()?)?;
+    let auth_token = match v["auth_token"].as_str() {
+        Some(x) => x.to_string(),
+        None => {
+            return Err(BridgeError::Auth(
+                "Joplin didn't give an auth token, is it new enough?".into(),
+            ))
+        }
+    };
+
+    println!("Please allow feather-joplin-bridge in Joplin, it's waiting for you there");
+
+    let deadline = Instant::now() + AUTH_WAIT;
+    loop {
+        let resp = client
+            .get(format!("{}/auth/check?auth_token={}", base_url, auth_token))
+            .send()?;
+        let v: Value = serde_json::from_str(&resp.text()?)?;
+        match v["status"].as_str() {
+            Some("accepted") => match v["token"].as_str() {
+                Some(token) => {
+                    info!("Joplin accepted the bridge");
+                    return Ok(token.to_string());
+                }
+                None => return Err(BridgeError::Auth("Accepted, but no token given".into())),
+            },
+            Some("rejected") => {
+                return Err(BridgeError::Auth("Access was rejected in Joplin".into()))
+            }
+            _ if Instant::now() >= deadline => {
+                return Err(BridgeError::Auth(format!(
+                    "Nobody allowed the bridge in Joplin within {} seconds",
+                    AUTH_WAIT.as_secs()
+                )))
+            }
+            _ => {
+                debug!("Still waiting for the user to accept");
+                sleep(Duration::from_secs(1));
+            }
+        }
+    }
+}
+
+// --token / JOPLIN_TOKEN first, then the cached one, then asking Joplin for a new one.
+// A dry run doesn't ask, that would change Joplin and the cache file
+pub fn get_token(
+    base_url: &str,
+    provided_token: Option<String>,
+    dry_run: bool,
+) -> Result<String, BridgeError> {
+    if let Some(token) = provided_token {
+        return Ok(token);
+    }
+
+    if let Some(token) = read_cached_token() {
+        if token_works(base_url, &token)? {
+            return Ok(token);
+        }
+        warn!("Cached token doesn't work anymore, asking Joplin for a new one");
+    }
+    if dry_run {
+        return Err(BridgeError::Auth(
+            "No working token, --dry-run doesn't ask Joplin for one. Use --token or run once without --dry-run".into(),
+        ));
+    }
+
+    let token = request_token(base_url)?;
+    if let Err(x) = write_cached_token(&token) {
+        warn!(
+            "Couldn't save the token, you will be asked again next time: {}",
+            x
+        );
+    }
+    Ok(token)
+}
+
+// Tokens in logs end up in bug reports
+pub fn redact_token(text: &str, token: &str) -> String {
+    if token.is_empty() {
+        return text.to_string();
+    }
+    text.replace(token, "<token>")
+}
@@ -1,21 +1,38 @@
+use crate::attachments::upload_attachments_md;
+use crate::converter::new_converter;
 use crate::data_types::feather::*;
 use crate::data_types::joplin::*;
-use crate::pandoc::convert_md_to_html;
+use crate::error::BridgeError;
+use crate::math::restore_math_images_md;
+use crate::plan::Plan;
+use crate::tags::{apply_node_tag, build_tags_tree, take_tag_line, TagMode};
+use crate::todo::{feather_name, parse_feather_name, take_due_header, todo_fields};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
 
-pub fn overwrite_joplin_to_feathernotes(
-    mut feather_file: FeatherStruct,
-    mut joplin_struct: JoplinData,
-    joplin_folders: Vec<FoldersArray>,
-    output_file: &str,
-) {
-    info!("Writing directories");
+// A note that will be fetched, converted and written at path
+pub struct PlannedNote {
+    pub id: String,
+    pub title: String,
+    pub path: Vec<String>, // Folder titles, without the note itself
+}
 
-    // This exist because when writing, it tries to write every node at path, which takes time. Here it will check if it already written
-    let mut written_dir_list_id: Vec<String> = Vec::new();
+// Folders are only names so they go into the tree right away, it also tells which notes would be duplicates.
+// Nothing is fetched, converted or written here
+pub fn plan_joplin_to_feathernotes(
+    feather_file: &mut FeatherStruct,
+    joplin_struct: &mut JoplinData,
+    joplin_folders: &[FoldersArray],
+    written_dir_list_id: &mut Vec<String>,
+    plan: &mut Plan,
+) -> Result<Vec<PlannedNote>, BridgeError> {
+    info!("Writing directories");
 
     // Create the folder structure
-    for folder in &joplin_folders {
-        let path_vec = joplin_struct.get_path_folder(&folder.id).unwrap();
+    for folder in joplin_folders {
+        let path_vec = joplin_struct.get_path_folder(&folder.id)?;
         for path_index in 1..path_vec.len() + 1 {
             debug!("path_index: {}", path_index);
             let path_small = path_vec.chunks(path_index).next().unwrap();
@@ -35,7 +52,12 @@ pub fn overwrite_joplin_to_feathernotes(
                 &path_small.last().unwrap().title
             );
 
+            let full_path: Vec<String> = path_small.iter().map(|x| x.title.clone()).collect();
             let node_vec = &mut feather_file.struct_xml.node;
+            if node_at_path(node_vec, &full_path).is_none() {
+                plan.add("feather", "create folder", &full_path, None);
+            }
+
             create_node_at_path(
                 node_vec,
                 &path_small.last().unwrap().title,
@@ -43,51 +65,305 @@ pub fn overwrite_joplin_to_feathernotes(
                 path_item,
                 Option::None,
                 0,
-                &mut written_dir_list_id,
-            )
-            .unwrap();
+                written_dir_list_id,
+            )?;
         }
         //let notes = joplin_struct.get_notes_of_folder(&folder.id).unwrap();
         //debug!("Got {} notes of folder {}", notes.len(), folder.title);
     }
     //feather_file.log_feather("");
 
-    info!("Writing notes");
-
-    // Manage notes
-    for folder in &joplin_folders {
-        let notes = joplin_struct.get_notes_of_folder(&folder.id).unwrap();
+    let mut planned_notes: Vec<PlannedNote> = Vec::new();
+    for folder in joplin_folders {
+        let notes = joplin_struct.get_notes_of_folder(&folder.id)?;
 
         for note in notes {
-            let body_md = joplin_struct.get_note_body(&note.id).unwrap();
-
-            let body = convert_md_to_html(body_md);
-
-            let parent = joplin_struct.get_parent_of_note(note.id).unwrap();
-
-            let path_small = joplin_struct.get_path_folder(&parent).unwrap();
+            let path_small = joplin_struct.get_path_folder(&note.parent_id)?;
 
             let mut path_item: Vec<String> = Vec::new();
             for item in path_small.iter() {
                 path_item.push(item.title.clone());
             }
 
-            debug!("Note with title: {} has path: {:?}", note.title, path_item);
+            let name = feather_name(&note);
+            debug!("Note with title: {} has path: {:?}", name, path_item);
 
-            let node_vec = &mut feather_file.struct_xml.node;
-            create_node_at_path(
-                node_vec,
-                &note.title,
-                &body,
-                path_item,
-                Option::None,
-                0,
-                &mut written_dir_list_id,
-            )
-            .unwrap();
+            let mut full_path = path_item.clone();
+            full_path.push(name.clone());
+            let duplicate = node_at_path(&feather_file.struct_xml.node, &full_path).is_some()
+                || planned_notes
+                    .iter()
+                    .any(|x| x.path == path_item && x.title == name);
 
-            info!("Done writing note: {}", note.title);
+            if duplicate {
+                plan.add("feather", "skip duplicate", &full_path, None);
+            } else {
+                plan.add("feather", "add note", &full_path, None);
+                planned_notes.push(PlannedNote {
+                    id: note.id,
+                    title: name,
+                    path: path_item,
+                });
+            }
+        }
+    }
+
+    Ok(planned_notes)
+}
+
+// Bodies are fetched and converted by joplin_struct.jobs workers in any order, nodes are still
+// made in the planned one so the file comes out the same every time
+pub fn write_planned_notes(
+    feather_file: &mut FeatherStruct,
+    joplin_struct: &JoplinData,
+    planned_notes: Vec<PlannedNote>,
+    written_dir_list_id: &mut Vec<String>,
+) -> Result<(), BridgeError> {
+    let jobs = joplin_struct.jobs.clamp(1, planned_notes.len().max(1));
+    info!("Writing notes, {} at a time", jobs);
+
+    let next = AtomicUsize::new(0);
+    let (sender, receiver) = mpsc::channel();
+    std::thread::scope(|scope| {
+        for _ in 0..jobs {
+            let sender = sender.clone();
+            let (next, notes) = (&next, &planned_notes);
+            // Stops when everything is taken, or when the receiver gave up after an error
+            scope.spawn(move || loop {
+                let index = next.fetch_add(1, Ordering::Relaxed);
+                if index >= notes.len() {
+                    break;
+                }
+                let body = joplin_struct.get_note_html(&notes[index].id);
+                if sender.send((index, body)).is_err() {
+                    break;
+                }
+            });
+        }
+        drop(sender);
+
+        let mut waiting: HashMap<usize, String> = HashMap::new();
+        let mut written = 0;
+        for (index, body) in receiver {
+            waiting.insert(index, body?);
+            while let Some(body) = waiting.remove(&written) {
+                write_planned_note(
+                    feather_file,
+                    joplin_struct,
+                    &planned_notes[written],
+                    &body,
+                    written_dir_list_id,
+                )?;
+                written += 1;
+            }
         }
+        Ok(())
+    })
+}
+
+fn write_planned_note(
+    feather_file: &mut FeatherStruct,
+    joplin_struct: &JoplinData,
+    note: &PlannedNote,
+    body: &str,
+    written_dir_list_id: &mut Vec<String>,
+) -> Result<(), BridgeError> {
+    let node_vec = &mut feather_file.struct_xml.node;
+    create_node_at_path(
+        node_vec,
+        &note.title,
+        body,
+        note.path.clone(),
+        Option::None,
+        0,
+        written_dir_list_id,
+    )?;
+
+    let mut path = note.path.clone();
+    path.push(note.title.clone());
+    if let Some(node) = node_at_path_mut(node_vec, &path) {
+        apply_node_tag(node, joplin_struct, &note.id);
     }
-    feather_file.write_file(output_file);
+    info!("Done writing note: {}", note.title);
+    Ok(())
+}
+
+pub fn overwrite_joplin_to_feathernotes(
+    mut feather_file: FeatherStruct,
+    mut joplin_struct: JoplinData,
+    joplin_folders: Vec<FoldersArray>,
+    output_file: &str,
+    tags_tree: Option<String>,
+    backups: usize,
+    dry_run: bool,
+) -> Result<Plan, BridgeError> {
+    let mut plan = Plan::new(dry_run);
+
+    // This exist because when writing, it tries to write every node at path, which takes time. Here it will check if it already written
+    let mut written_dir_list_id: Vec<String> = Vec::new();
+
+    let planned_notes = plan_joplin_to_feathernotes(
+        &mut feather_file,
+        &mut joplin_struct,
+        &joplin_folders,
+        &mut written_dir_list_id,
+        &mut plan,
+    )?;
+    if let Some(root) = &tags_tree {
+        plan.add("feather", "rebuild tags", std::slice::from_ref(root), None);
+    }
+    plan.add("file", "write", &[], Some(output_file.to_string()));
+
+    if dry_run {
+        return Ok(plan);
+    }
+
+    write_planned_notes(
+        &mut feather_file,
+        &joplin_struct,
+        planned_notes,
+        &mut written_dir_list_id,
+    )?;
+    if let Some(root) = &tags_tree {
+        build_tags_tree(&mut feather_file, &joplin_struct, &joplin_folders, root)?;
+    }
+    feather_file.write_file(output_file, backups)?;
+    Ok(plan)
+}
+
+pub fn overwrite_feathernotes_to_joplin(
+    feather_file: FeatherStruct,
+    mut joplin_struct: JoplinData,
+    joplin_folder_id: Option<String>,
+    dry_run: bool,
+) -> Result<Plan, BridgeError> {
+    let mut plan = Plan::new(dry_run);
+    let root_id = joplin_folder_id.unwrap_or_default();
+
+    info!("Writing feather nodes to joplin");
+
+    for node in &feather_file.struct_xml.node {
+        write_node_to_joplin(&mut joplin_struct, node, &root_id, &[], &mut plan)?;
+    }
+    Ok(plan)
+}
+
+// Attachments in the feather body become joplin resources, math images TeX again
+pub fn feather_body_to_joplin(
+    joplin_struct: &JoplinData,
+    text: &str,
+) -> Result<String, BridgeError> {
+    let converter = new_converter(joplin_struct.converter, joplin_struct.math);
+    let body = restore_math_images_md(converter.html_to_md(text.to_string())?);
+    upload_attachments_md(body, joplin_struct, &joplin_struct.attachments)
+}
+
+// Everything joplin gets for a feather node: title and to-do fields from the name, the body
+// without the due header. joplin_id is the note it will update, if there is one
+pub fn feather_note_to_joplin(
+    joplin_struct: &JoplinData,
+    name: &str,
+    text: &str,
+    joplin_id: Option<&str>,
+) -> Result<Value, BridgeError> {
+    let existing = joplin_id.and_then(|id| joplin_struct.note(id));
+    let mut body = feather_body_to_joplin(joplin_struct, text)?;
+    if joplin_struct.tag_mode == TagMode::Line {
+        body = take_tag_line(&body);
+    }
+    // Only to-dos have the header, a normal note could start with "Due:" by itself
+    let (body, due) = match parse_feather_name(name).1 {
+        Some(_) => take_due_header(&body),
+        None => (body, 0),
+    };
+
+    let mut item = todo_fields(name, existing);
+    item["body"] = json!(body);
+    item["todo_due"] = json!(due);
+    Ok(item)
+}
+
+// Folders written by overwrite_joplin_to_feathernotes have only the joplin id as text
+pub fn is_joplin_id(text: &str) -> bool {
+    text.len() == 32 && text.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+// Nodes with children become folders, the rest are notes
+fn write_node_to_joplin(
+    joplin_struct: &mut JoplinData,
+    node: &Node,
+    parent_id: &str,
+    parent_path: &[String],
+    plan: &mut Plan,
+) -> Result<(), BridgeError> {
+    let mut path = parent_path.to_vec();
+    path.push(node.name.clone());
+
+    if node.node.is_empty() {
+        let duplicate = joplin_struct
+            .child_notes(parent_id)
+            .any(|x| x.title == parse_feather_name(&node.name).0);
+        if duplicate {
+            warn!("Avoiding writing duplicate of title: {}", node.name);
+            plan.add("joplin", "skip duplicate", &path, None);
+            return Ok(());
+        }
+
+        plan.add("joplin", "add note", &path, None);
+        if !plan.dry_run {
+            let item =
+                feather_note_to_joplin(joplin_struct, &node.name, node.text.as_str()?, None)?;
+            joplin_struct.create_note(item, parent_id)?;
+            info!("Done writing note: {}", node.name);
+        }
+        return Ok(());
+    }
+
+    let existing_folder = joplin_struct
+        .child_folders(parent_id)
+        .find(|x| x.title == node.name)
+        .map(|x| x.id.clone());
+
+    let folder_id = match existing_folder {
+        Some(id) => {
+            debug!("Folder exists, using it: \"{}\"", node.name);
+            id
+        }
+        None => {
+            plan.add("joplin", "create folder", &path, None);
+            if plan.dry_run {
+                // Nothing can be in a folder that doesn't exist, so no duplicates will be found in it
+                format!("dry-run-{}", plan.steps.len())
+            } else {
+                joplin_struct.create_folder(&node.name, parent_id)?
+            }
+        }
+    };
+
+    // FeatherNotes allows text in nodes with children, Joplin folders can't have it so it gets its own note
+    let text = node.text.as_str()?.trim();
+    if !text.is_empty() && !is_joplin_id(text) {
+        let duplicate = joplin_struct
+            .child_notes(&folder_id)
+            .any(|x| x.title == node.name);
+        if !duplicate {
+            plan.add(
+                "joplin",
+                "add note",
+                &path,
+                Some("text of the folder node".into()),
+            );
+            if !plan.dry_run {
+                let item =
+                    feather_note_to_joplin(joplin_struct, &node.name, node.text.as_str()?, None)?;
+                joplin_struct.create_note(item, &folder_id)?;
+            }
+        }
+    }
+
+    for child in &node.node {
+        write_node_to_joplin(joplin_struct, child, &folder_id, &path, plan)?;
+    }
+
+    Ok(())
 }
@@ -0,0 +1,24 @@
+use crate::error::BridgeError;
+use crate::math::MathMode;
+use crate::native::NativeConverter;
+use crate::pandoc::PandocConverter;
+
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConverterKind {
+    Native, // Pure rust, no pandoc binary needed, knows FeatherNotes' Qt html
+    Pandoc, // External pandoc process, better with unusual markdown
+}
+
+// Joplin markdown to the html FeatherNotes keeps in its nodes and back.
+// The md is already through JoplinData::get_note_body
+pub trait Converter {
+    fn md_to_html(&self, md: String) -> Result<String, BridgeError>;
+    fn html_to_md(&self, html: String) -> Result<String, BridgeError>;
+}
+
+pub fn new_converter(kind: ConverterKind, math: MathMode) -> Box<dyn Converter> {
+    match kind {
+        ConverterKind::Native => Box::new(NativeConverter { math }),
+        ConverterKind::Pandoc => Box::new(PandocConverter { math }),
+    }
+}
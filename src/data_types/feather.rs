@@ -1,70 +1,130 @@
-use crate::pandoc::*;
-use quick_xml::de::from_str;
-use quick_xml::events::Event;
+use crate::error::BridgeError;
+use chrono::Local;
+use quick_xml::escape::unescape;
+use quick_xml::events::{BytesStart, Event};
 use quick_xml::reader::Reader;
-use serde::Deserialize;
-use serde::Serialize;
-use std::error::*;
-use std::fmt;
-use std::fs::OpenOptions;
-use std::io::Write;
+use quick_xml::writer::Writer;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, OnceLock};
+
+// Read and written by hand, serde would drop whatever isn't a field here
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct Node {
+    pub name: String,
+    pub collapse: Option<String>, // At default 1 to improve performance on kobos
+    pub tag: Option<String>,
+    pub icon: Option<String>, // Base64 png shown in front of the name
+    pub attributes: Vec<(String, String)>, // Unknown ones, written back as they were
+    pub text: NodeText,
+    pub node: Vec<Node>, // https://github.com/tafia/quick-xml/issues/510
+    pub elements: Vec<(usize, String)>, // Unknown child elements as raw xml, after that many nodes
+}
+
+// Where a lazy body is in the file, still escaped and with the whitespace around it
+#[derive(Debug, Clone)]
+struct TextSource {
+    path: Arc<PathBuf>,
+    start: u64,
+    end: u64,
+}
+
+// Body of a node. In lazy mode it stays in the file until something reads it, so the file
+// must not change before that. Writing the file reads every body first
+#[derive(Debug, Default, Clone)]
+pub struct NodeText {
+    text: OnceLock<String>,
+    source: Option<TextSource>,
+}
 
-use quick_xml::se::to_string;
+impl NodeText {
+    fn lazy(source: TextSource) -> NodeText {
+        NodeText {
+            text: OnceLock::new(),
+            source: Some(source),
+        }
+    }
 
-// https://stackoverflow.com/questions/51550167/how-to-manually-return-a-result-boxdyn-error
-#[derive(Debug)]
-struct MyError(String);
+    // Reads a lazy body the first time, a file changed or gone since is an error
+    pub fn as_str(&self) -> Result<&str, BridgeError> {
+        if let Some(text) = self.text.get() {
+            return Ok(text);
+        }
+        let text = match &self.source {
+            Some(source) => load_text(source)?,
+            None => String::new(),
+        };
+        Ok(self.text.get_or_init(|| text))
+    }
+}
 
-impl fmt::Display for MyError {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}", self.0)
+// A body that can't be read isn't equal to anything
+impl PartialEq for NodeText {
+    fn eq(&self, other: &Self) -> bool {
+        match (self.as_str(), other.as_str()) {
+            (Ok(x), Ok(y)) => x == y,
+            _ => false,
+        }
     }
 }
 
-impl Error for MyError {}
+impl From<String> for NodeText {
+    fn from(text: String) -> NodeText {
+        NodeText {
+            text: OnceLock::from(text),
+            source: None,
+        }
+    }
+}
 
-#[derive(Debug, Default, Serialize, Deserialize, PartialEq, Clone)]
-pub struct Node {
-    #[serde(rename = "@name")]
-    pub name: String,
-    #[serde(rename = "@collapse")]
-    pub collapse: Option<String>, // At default 1 to improve performance on kobos
-    #[serde(rename = "$text")]
-    pub text: String,
-    #[serde(default)]
-    pub node: Vec<Node>, // https://github.com/tafia/quick-xml/issues/510
+impl From<&str> for NodeText {
+    fn from(text: &str) -> NodeText {
+        NodeText::from(text.to_string())
+    }
 }
 
-#[derive(Debug, Default, Serialize, Deserialize, PartialEq, Clone)]
+// A body as reading the written file gives it back. The reader trims the whitespace around
+// it, a \r is written as &#xd; so that one stays
+pub fn trim_body(text: &str) -> &str {
+    text.trim_matches(|c| matches!(c, ' ' | '\t' | '\n'))
+}
+
+fn load_text(source: &TextSource) -> Result<String, BridgeError> {
+    let mut file = File::open(&*source.path)?;
+    file.seek(SeekFrom::Start(source.start))?;
+    let mut raw = vec![0; (source.end - source.start) as usize];
+    file.read_exact(&mut raw)?;
+    let raw = String::from_utf8(raw)?;
+    // Same as trim_text of the reader
+    let raw = raw.trim_matches(|c| matches!(c, ' ' | '\t' | '\r' | '\n'));
+    match unescape(raw) {
+        Ok(x) => Ok(x.to_string()),
+        Err(x) => Err(BridgeError::Xml(x.to_string())),
+    }
+}
+
+#[derive(Debug, Default, PartialEq, Clone)]
 #[warn(non_camel_case_types)]
 #[allow(non_camel_case_types)]
 pub struct feathernotes {
     pub node: Vec<Node>,
-    #[serde(rename = "@txtfont")]
     pub txtfont: String,
-    #[serde(rename = "@nodefont")]
     pub nodefont: String,
+    pub attributes: Vec<(String, String)>,
+    pub elements: Vec<(usize, String)>,
 }
 
-#[derive(Debug, Default, Serialize, Deserialize, PartialEq, Clone)]
+#[derive(Debug, Default, PartialEq, Clone)]
 pub struct FeatherStruct {
     pub struct_xml: feathernotes,
 }
 
 impl FeatherStruct {
-    pub fn write_file(&self, title: &str) {
+    pub fn write_file(&self, title: &str, backups: usize) -> Result<(), BridgeError> {
         info!("Writing feather file to {}", title);
-
-        let struct_copy = self.struct_xml.clone();
-        let xml = final_touches_xml(to_string(&struct_copy).unwrap());
-
-        let mut file = OpenOptions::new()
-        .write(true)
-        .create(true)
-        .open(title)
-        .unwrap();
-
-        file.write_all(xml.as_bytes()).unwrap();
+        let xml = feathernotes_to_xml(&self.struct_xml)?;
+        replace_file(Path::new(title), xml.as_bytes(), backups)
     }
 
     pub fn new() -> FeatherStruct {
@@ -72,54 +132,493 @@ impl FeatherStruct {
             node: Vec::new(),
             txtfont: String::from("Monospace,11,-1,5,400,0,0,0,0,0,0,0,0,0,0,1"),
             nodefont: String::from("Cantarell,11,-1,5,400,0,0,0,0,0,0,0,0,0,0,1"),
+            attributes: Vec::new(),
+            elements: Vec::new(),
         };
 
         FeatherStruct {
             struct_xml: feather_inner,
         }
     }
-    pub fn read(provided_path: String) -> Result<FeatherStruct, Box<dyn Error>> {
+    // The file is read as a stream, lazy leaves node bodies in it until they are needed
+    pub fn read(provided_path: String, lazy: bool) -> Result<FeatherStruct, BridgeError> {
         debug!("Provided path for feather XML file: {}", provided_path);
 
-        if log_enabled!(log::Level::Debug) {
-            let mut buf = Vec::new();
-            let mut reader_new = Reader::from_file(provided_path.clone()).unwrap();
-            reader_new.trim_text(true);
-            // Debug thing
-            loop {
-                match reader_new.read_event_into(&mut buf) {
-                    Err(e) => error!(
-                        "Error at position {}: {:?}",
-                        reader_new.buffer_position(),
-                        e
-                    ),
-                    Ok(Event::Eof) => {
-                        debug!("End of file");
-                        break;
-                    }
-                    Ok(Event::Start(e)) => {
-                        debug!("Event start {:#?}", e)
-                    }
-                    Ok(Event::Text(e)) => {
-                        debug!("Text start {:#?}", e)
-                    }
-                    _ => (),
+        let mut file = BufReader::new(File::open(&provided_path)?);
+        let source = lazy.then(|| Arc::new(PathBuf::from(&provided_path)));
+        // The reader skips a BOM without counting it
+        let bom = file.fill_buf()?.starts_with(b"\xEF\xBB\xBF");
+        let mut reader = FnxReader::new(file, source);
+        if bom {
+            reader.offset = 3;
+        }
+        let feathernotes_from_file = reader.read()?;
+
+        let mut count = Vec::new();
+        collect_node_paths(&feathernotes_from_file.node, &[], &mut count);
+        debug!("Read {} nodes from {}", count.len(), provided_path);
+
+        let new_feather = FeatherStruct {
+            struct_xml: feathernotes_from_file,
+        };
+
+        Ok(new_feather)
+    }
+}
+
+fn file_name_of(path: &Path) -> Result<String, BridgeError> {
+    match path.file_name() {
+        Some(x) => Ok(x.to_string_lossy().to_string()),
+        None => Err(BridgeError::Xml(format!(
+            "Not a file name: {}",
+            path.display()
+        ))),
+    }
+}
+
+// Written next to the target and renamed over it, so a crash never leaves half a file.
+// The old file is copied to a backup first
+pub fn replace_file(path: &Path, contents: &[u8], backups: usize) -> Result<(), BridgeError> {
+    let tmp_path = path.with_file_name(format!(
+        ".{}.{}.tmp",
+        file_name_of(path)?,
+        std::process::id()
+    ));
+
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(&tmp_path)?;
+    let written = file.write_all(contents).and_then(|_| file.sync_all());
+    if let Err(e) = written {
+        std::fs::remove_file(&tmp_path).ok();
+        return Err(BridgeError::Io(e));
+    }
+    drop(file);
+
+    if backups > 0 && path.exists() {
+        backup_file(path, backups)?;
+    }
+    if let Err(e) = std::fs::rename(&tmp_path, path) {
+        std::fs::remove_file(&tmp_path).ok();
+        return Err(BridgeError::Io(e));
+    }
+    // The rename itself is only safe once the directory is synced
+    #[cfg(unix)]
+    std::fs::File::open(dir_of(path))?.sync_all()?;
+    Ok(())
+}
+
+// A bare file name is in the current directory
+fn dir_of(path: &Path) -> &Path {
+    match path.parent() {
+        Some(x) if !x.as_os_str().is_empty() => x,
+        _ => Path::new("."),
+    }
+}
+
+// file.fnx.20230410-153000.bak, only the newest ones are kept
+fn backup_file(path: &Path, backups: usize) -> Result<(), BridgeError> {
+    let name = file_name_of(path)?;
+    let backup = path.with_file_name(format!(
+        "{}.{}.bak",
+        name,
+        Local::now().format("%Y%m%d-%H%M%S")
+    ));
+    debug!("Backing up {} to {}", path.display(), backup.display());
+    std::fs::copy(path, &backup)?;
+
+    let prefix = format!("{}.", name);
+    let mut old: Vec<PathBuf> = std::fs::read_dir(dir_of(path))?
+        .filter_map(|x| x.ok())
+        .map(|x| x.path())
+        .filter(|x| {
+            let file = x.file_name().unwrap_or_default().to_string_lossy();
+            file.starts_with(&prefix) && file.ends_with(".bak")
+        })
+        .collect();
+    // The timestamp sorts by name
+    old.sort();
+    while old.len() > backups {
+        let oldest = old.remove(0);
+        info!("Removing old backup {}", oldest.display());
+        std::fs::remove_file(oldest)?;
+    }
+    Ok(())
+}
+
+impl Node {
+    pub fn new(title: &str, body: &str) -> Node {
+        Node {
+            name: title.to_string(),
+            collapse: Some("1".to_string()),
+            text: body.into(),
+            ..Default::default()
+        }
+    }
+}
+
+// Known attributes go to their field, the rest is kept in order
+fn read_attributes(
+    start: &BytesStart,
+    mut known: impl FnMut(&str, String) -> bool,
+) -> Result<Vec<(String, String)>, BridgeError> {
+    let mut unknown = Vec::new();
+    for attribute in start.attributes() {
+        let attribute = attribute?;
+        let key = String::from_utf8(attribute.key.as_ref().to_vec())?;
+        let value = attribute.unescape_value()?.to_string();
+        if !known(&key, value.clone()) {
+            debug!("Keeping unknown attribute {}", key);
+            unknown.push((key, value));
+        }
+    }
+    Ok(unknown)
+}
+
+// Builds the tree while going through the events, only one event is in memory at a time
+struct FnxReader<R: BufRead> {
+    reader: Reader<R>,
+    buf: Vec<u8>,
+    lazy: Option<Arc<PathBuf>>, // File the bodies are left in
+    offset: u64,                // Bytes before what the reader counts
+}
+
+impl<R: BufRead> FnxReader<R> {
+    fn new(source: R, lazy: Option<Arc<PathBuf>>) -> FnxReader<R> {
+        let mut reader = Reader::from_reader(source);
+        reader.trim_text(true);
+        FnxReader {
+            reader,
+            buf: Vec::new(),
+            lazy,
+            offset: 0,
+        }
+    }
+
+    // With where it started in the file
+    fn next(&mut self) -> Result<(u64, Event<'static>), BridgeError> {
+        self.buf.clear();
+        let start = self.reader.buffer_position() as u64 + self.offset;
+        let event = self.reader.read_event_into(&mut self.buf)?.into_owned();
+        Ok((start, event))
+    }
+
+    fn read(mut self) -> Result<feathernotes, BridgeError> {
+        loop {
+            let (empty, e) = match self.next()?.1 {
+                Event::Start(e) if e.name().as_ref() == b"feathernotes" => (false, e),
+                // <feathernotes .../> is a file without nodes
+                Event::Empty(e) if e.name().as_ref() == b"feathernotes" => (true, e),
+                Event::Eof => {
+                    return Err(BridgeError::Xml(
+                        "No feathernotes element in the feather file".into(),
+                    ))
+                }
+                _ => continue,
+            };
+            let mut root = feathernotes::default();
+            root.attributes = read_attributes(&e, |key, value| {
+                match key {
+                    "txtfont" => root.txtfont = value,
+                    "nodefont" => root.nodefont = value,
+                    _ => return false,
                 }
-                // if we don't keep a borrow elsewhere, we can clear the buffer to keep memory usage low
-                buf.clear();
+                true
+            })?;
+            if !empty {
+                // The root has no text in FeatherNotes, anything there is dropped
+                let mut text = NodeText::default();
+                self.read_content(&mut text, &mut root.node, &mut root.elements)?;
             }
+            return Ok(root);
         }
+    }
 
-        let feathernotes_from_str: feathernotes =
-            from_str(std::fs::read_to_string(provided_path).unwrap().as_ref()).unwrap();
+    // Raw xml of an element the reader doesn't know, start is already read
+    fn read_element(&mut self, start: Event<'static>) -> Result<String, BridgeError> {
+        let mut writer = Writer::new(Vec::new());
+        let mut depth = match &start {
+            Event::Start(e) => {
+                warn!(
+                    "Unknown element <{}> in the feather file, keeping it as is",
+                    String::from_utf8_lossy(e.name().as_ref())
+                );
+                1
+            }
+            _ => 0,
+        };
+        writer.write_event(start)?;
+        while depth > 0 {
+            let event = self.next()?.1;
+            match &event {
+                Event::Start(_) => depth += 1,
+                Event::End(_) => depth -= 1,
+                Event::Eof => return Err(BridgeError::Xml("Feather file ended too early".into())),
+                _ => (),
+            }
+            writer.write_event(event)?;
+        }
+        Ok(String::from_utf8(writer.into_inner())?)
+    }
 
-        debug!("Parsed struct: {:#?}", feathernotes_from_str);
+    // Children and text of a node or of the root, until its end tag
+    fn read_content(
+        &mut self,
+        text: &mut NodeText,
+        nodes: &mut Vec<Node>,
+        elements: &mut Vec<(usize, String)>,
+    ) -> Result<(), BridgeError> {
+        // A body in one piece can stay in the file, more pieces are joined here
+        let mut body = String::new();
+        let mut source: Option<TextSource> = None;
+        let mut pieces = 0;
+
+        loop {
+            let (start, event) = self.next()?;
+            let piece = match event {
+                Event::Start(e) if e.name().as_ref() == b"node" => {
+                    nodes.push(self.read_node(&e, false)?);
+                    continue;
+                }
+                Event::Empty(e) if e.name().as_ref() == b"node" => {
+                    nodes.push(self.read_node(&e, true)?);
+                    continue;
+                }
+                event @ (Event::Start(_) | Event::Empty(_)) => {
+                    elements.push((nodes.len(), self.read_element(event)?));
+                    continue;
+                }
+                Event::Text(e) => match &self.lazy {
+                    Some(path) if pieces == 0 => {
+                        source = Some(TextSource {
+                            path: path.clone(),
+                            start,
+                            end: self.reader.buffer_position() as u64 + self.offset,
+                        });
+                        None
+                    }
+                    _ => Some(e.unescape()?.to_string()),
+                },
+                Event::CData(e) => Some(String::from_utf8(e.into_inner().to_vec())?),
+                Event::End(_) => break,
+                Event::Eof => return Err(BridgeError::Xml("Feather file ended too early".into())),
+                _ => continue,
+            };
+            pieces += 1;
+            if let Some(piece) = piece {
+                if let Some(first) = source.take() {
+                    body = load_text(&first)?;
+                }
+                body.push_str(&piece);
+            }
+        }
 
-        let new_feather = FeatherStruct {
-            struct_xml: feathernotes_from_str,
+        *text = match source {
+            Some(source) => NodeText::lazy(source),
+            None => body.into(),
         };
+        Ok(())
+    }
 
-        Ok(new_feather)
+    fn read_node(&mut self, start: &BytesStart, empty: bool) -> Result<Node, BridgeError> {
+        let mut node = Node::default();
+        node.attributes = read_attributes(start, |key, value| {
+            match key {
+                "name" => node.name = value,
+                "collapse" => node.collapse = Some(value),
+                "tag" => node.tag = Some(value),
+                "icon" => node.icon = Some(value),
+                _ => return false,
+            }
+            true
+        })?;
+        if !empty {
+            self.read_content(&mut node.text, &mut node.node, &mut node.elements)?;
+        }
+        Ok(node)
+    }
+}
+
+// Same as QDom in FeatherNotes, text keeps > and quotes as they are. Only the > of ]]> is
+// escaped, it isn't allowed in text
+fn escape_text(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace("]]>", "]]&gt;")
+        .replace('\r', "&#xd;")
+}
+
+// Whitespace in attributes is escaped too, a reader would turn it into spaces
+fn escape_attribute(value: &str) -> String {
+    escape_text(value)
+        .replace('"', "&quot;")
+        .replace('\n', "&#xa;")
+        .replace('\t', "&#x9;")
+}
+
+fn write_attributes(xml: &mut String, attributes: &[(&str, &str)]) {
+    for (key, value) in attributes {
+        xml.push_str(&format!(" {}=\"{}\"", key, escape_attribute(value)));
+    }
+}
+
+// One space of indent per level like FeatherNotes, text right after the start tag so it
+// stays as it is. The indent after it is trimmed when reading
+fn write_node(xml: &mut String, node: &Node, depth: usize) -> Result<(), BridgeError> {
+    let indent = " ".repeat(depth);
+    let mut attributes = vec![("name", node.name.as_str())];
+    for (key, value) in [
+        ("collapse", &node.collapse),
+        ("tag", &node.tag),
+        ("icon", &node.icon),
+    ] {
+        if let Some(value) = value {
+            attributes.push((key, value.as_str()));
+        }
+    }
+    attributes.extend(
+        node.attributes
+            .iter()
+            .map(|(k, v)| (k.as_str(), v.as_str())),
+    );
+
+    xml.push_str(&format!("\n{}<node", indent));
+    write_attributes(xml, &attributes);
+    xml.push('>');
+    xml.push_str(&escape_text(node.text.as_str()?));
+    write_children(xml, &node.node, &node.elements, depth + 1)?;
+    if !node.node.is_empty() || !node.elements.is_empty() {
+        xml.push_str(&format!("\n{}", indent));
+    }
+    xml.push_str("</node>");
+    Ok(())
+}
+
+// Unknown elements go back between the nodes they were read between
+fn write_children(
+    xml: &mut String,
+    nodes: &[Node],
+    elements: &[(usize, String)],
+    depth: usize,
+) -> Result<(), BridgeError> {
+    let indent = " ".repeat(depth);
+    let mut elements = elements.iter().peekable();
+    for (i, child) in nodes.iter().enumerate() {
+        while let Some((_, element)) = elements.next_if(|x| x.0 <= i) {
+            xml.push_str(&format!("\n{}{}", indent, element));
+        }
+        write_node(xml, child, depth)?;
+    }
+    for (_, element) in elements {
+        xml.push_str(&format!("\n{}{}", indent, element));
+    }
+    Ok(())
+}
+
+// Lazy bodies are read here, before anything is written over the file they are in
+pub fn feathernotes_to_xml(root: &feathernotes) -> Result<String, BridgeError> {
+    let mut attributes = vec![
+        ("txtfont", root.txtfont.as_str()),
+        ("nodefont", root.nodefont.as_str()),
+    ];
+    attributes.extend(
+        root.attributes
+            .iter()
+            .map(|(k, v)| (k.as_str(), v.as_str())),
+    );
+
+    let mut xml = String::from("<?xml version='1.0' encoding='UTF-8'?>\n<feathernotes");
+    write_attributes(&mut xml, &attributes);
+    xml.push('>');
+    write_children(&mut xml, &root.node, &root.elements, 1)?;
+    xml.push_str("\n</feathernotes>\n");
+    Ok(xml)
+}
+
+// Path is made of node names, starting at root. Empty path is not a node
+pub fn node_at_path<'a>(nodes: &'a [Node], path: &[String]) -> Option<&'a Node> {
+    let (first, rest) = path.split_first()?;
+    let node = nodes.iter().find(|x| &x.name == first)?;
+    if rest.is_empty() {
+        Some(node)
+    } else {
+        node_at_path(&node.node, rest)
+    }
+}
+
+pub fn node_at_path_mut<'a>(nodes: &'a mut [Node], path: &[String]) -> Option<&'a mut Node> {
+    let (first, rest) = path.split_first()?;
+    let node = nodes.iter_mut().find(|x| &x.name == first)?;
+    if rest.is_empty() {
+        Some(node)
+    } else {
+        node_at_path_mut(&mut node.node, rest)
+    }
+}
+
+// Creates every missing node on the way, the ones created get the text from texts (same index as path)
+pub fn ensure_node_at_path<'a>(
+    nodes: &'a mut Vec<Node>,
+    path: &[String],
+    texts: &[String],
+) -> Option<&'a mut Node> {
+    let (first, rest) = path.split_first()?;
+    let index = match nodes.iter().position(|x| &x.name == first) {
+        Some(x) => x,
+        None => {
+            debug!("Creating node \"{}\" on the way", first);
+            let text = texts.first().cloned().unwrap_or_default();
+            nodes.push(Node::new(first, &text));
+            nodes.len() - 1
+        }
+    };
+    if rest.is_empty() {
+        Some(&mut nodes[index])
+    } else {
+        ensure_node_at_path(
+            &mut nodes[index].node,
+            rest,
+            texts.get(1..).unwrap_or_default(),
+        )
+    }
+}
+
+pub fn remove_node_at_path(nodes: &mut Vec<Node>, path: &[String]) -> Option<Node> {
+    let (last, parent_path) = path.split_last()?;
+    let siblings = if parent_path.is_empty() {
+        nodes
+    } else {
+        &mut node_at_path_mut(nodes, parent_path)?.node
+    };
+    let index = siblings.iter().position(|x| &x.name == last)?;
+    Some(siblings.remove(index))
+}
+
+// Missing parents are created empty
+pub fn insert_node_at_path(nodes: &mut Vec<Node>, parent_path: &[String], node: Node) {
+    if parent_path.is_empty() {
+        nodes.push(node);
+        return;
+    }
+    let texts = vec![String::new(); parent_path.len()];
+    if let Some(parent) = ensure_node_at_path(nodes, parent_path, &texts) {
+        parent.node.push(node);
+    }
+}
+
+// Every node with its path, parents before children
+pub fn collect_node_paths<'a>(
+    nodes: &'a [Node],
+    prefix: &[String],
+    storage: &mut Vec<(Vec<String>, &'a Node)>,
+) {
+    for node in nodes {
+        let mut path = prefix.to_vec();
+        path.push(node.name.clone());
+        storage.push((path.clone(), node));
+        collect_node_paths(&node.node, &path, storage);
     }
 }
 
@@ -132,15 +631,14 @@ pub fn create_node_at_path(
     path_progress: Option<usize>, // if found, this gives the next index to look for
     children_count: usize,        // 0 is root, other are children, this gets bigger and bigger
     written_dir_list_id: &mut Vec<String>,
-) -> Result<(), Box<dyn Error>> {
-
+) -> Result<(), BridgeError> {
     if body.chars().count() == 32 && written_dir_list_id.contains(&body.to_string()) {
         debug!("Directory exists, skipping it: \"{}\"", title);
         return Ok(());
     }
 
     if children_count == 0 {
-        /* 
+        /*
         debug!(
             "Trying to write {} at {:?} with current node: {:?}",
             title, path, currect_node
@@ -150,21 +648,12 @@ pub fn create_node_at_path(
 
     let last_name_tmpvec = path.clone();
 
-    let mut last_name: String = String::new();
-
     // Check if writing at root is needed, then skip the rest
-    match last_name_tmpvec.last() {
-        Some(x) => {
-            last_name = x.clone();
-        }
+    let last_name: String = match last_name_tmpvec.last() {
+        Some(x) => x.clone(),
         None => {
             debug!("Writing at root of feather file");
-            let new_node = Node {
-                name: title.to_string(),
-                collapse: Some("1".to_string()),
-                text: body.to_string(),
-                node: Vec::new(),
-            };
+            let new_node = Node::new(title, body);
 
             let mut duplicate = false;
             for item in currect_node.iter_mut() {
@@ -181,12 +670,12 @@ pub fn create_node_at_path(
                     title.to_string()
                 );
             } else {
-                debug!("Avoiding writing duplicate of title: {}", title.to_string());
+                warn!("Avoiding writing duplicate of title: {}", title.to_string());
             }
 
             return Ok(());
         }
-    }
+    };
 
     let mut path_name_index = 0;
 
@@ -216,7 +705,7 @@ pub fn create_node_at_path(
                         path,
                         Some(path_name_index + 1),
                         children_count + 1,
-                        written_dir_list_id
+                        written_dir_list_id,
                     );
                     match result_child {
                         Ok(_) => {
@@ -226,7 +715,7 @@ pub fn create_node_at_path(
                             if children_count == 0 {
                                 debug!("What?????");
                             }
-                            return Err(Box::new(MyError(String::new())));
+                            return Err(BridgeError::Xml(String::new()));
                         }
                     }
                 } else {
@@ -234,18 +723,13 @@ pub fn create_node_at_path(
                     debug!("{}", &err);
                     if &node.name == path.last().unwrap() {
                         debug!("Testing!");
-                        let new_node = Node {
-                            name: title.to_string(),
-                            collapse: Some("1".to_string()),
-                            text: body.to_string(),
-                            node: Vec::new(),
-                        };
+                        let new_node = Node::new(title, body);
                         node.node.push(new_node);
                         written_dir_list_id.push(body.to_string());
                         return Ok(());
                     }
                     // Nope, acceptable. another child will find it... propably
-                    //return Err(Box::new(MyError(err)));
+                    //return Err(BridgeError::Xml(err));
                 }
             } else {
                 debug!(
@@ -264,12 +748,7 @@ pub fn create_node_at_path(
             debug!("Edge case?");
             for node in currect_node.iter_mut() {
                 if node.name == path[path_name_index] {
-                    let new_node = Node {
-                        name: title.to_string(),
-                        collapse: Some("1".to_string()),
-                        text: body.to_string(),
-                        node: Vec::new(),
-                    };
+                    let new_node = Node::new(title, body);
 
                     let mut duplicate = false;
                     for item in node.node.iter_mut() {
@@ -285,7 +764,7 @@ pub fn create_node_at_path(
                     if !duplicate {
                         node.node.push(new_node);
                     } else {
-                        debug!("Avoiding writing duplicate of title: {}", title.to_string());
+                        warn!("Avoiding writing duplicate of title: {}", title.to_string());
                     }
                     written_dir_list_id.push(body.to_string());
                     return Ok(());
@@ -293,12 +772,7 @@ pub fn create_node_at_path(
             }
         }
 
-        let new_node = Node {
-            name: title.to_string(),
-            collapse: Some("1".to_string()),
-            text: body.to_string(),
-            node: Vec::new(),
-        };
+        let new_node = Node::new(title, body);
 
         let mut duplicate = false;
         for item in currect_node.iter_mut() {
@@ -315,17 +789,156 @@ pub fn create_node_at_path(
                 title.to_string()
             );
         } else {
-            debug!("Avoiding writing duplicate of title: {}", title.to_string());
+            warn!("Avoiding writing duplicate of title: {}", title.to_string());
         }
         written_dir_list_id.push(body.to_string());
         return Ok(());
     }
 
     if children_count == 0 {
-        return Err(Box::new(MyError(format!(
+        return Err(BridgeError::Xml(format!(
             "Couldn't find requested folder name, {:?}",
             path
-        ))));
+        )));
+    }
+    Err(BridgeError::Xml(String::new()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn feathernotes_from_xml(xml: &str) -> Result<feathernotes, BridgeError> {
+        FnxReader::new(xml.as_bytes(), None).read()
+    }
+
+    fn round_trip(root: &feathernotes) -> feathernotes {
+        feathernotes_from_xml(&feathernotes_to_xml(root).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn examples_round_trip() {
+        for path in ["Feather-Nodes-example.fnx", "Feather-content-example.fnx"] {
+            let xml = std::fs::read_to_string(path).unwrap();
+            let read = feathernotes_from_xml(&xml).unwrap();
+            let text = read.node[0].text.as_str().unwrap();
+            assert!(text.starts_with("<!DOCTYPE HTML"), "{}", path);
+
+            let written = feathernotes_to_xml(&read).unwrap();
+            let read_again = feathernotes_from_xml(&written).unwrap();
+            assert_eq!(read, read_again, "{}", path);
+            assert_eq!(
+                written,
+                feathernotes_to_xml(&read_again).unwrap(),
+                "{}",
+                path
+            );
+        }
+    }
+
+    #[test]
+    fn empty_root_is_an_empty_tree() {
+        let xml = "<?xml version='1.0' encoding='UTF-8'?>\n\
+                   <feathernotes txtfont=\"Mono\" nodefont=\"Sans\"/>\n";
+        let read = feathernotes_from_xml(xml).unwrap();
+        assert!(read.node.is_empty());
+        assert_eq!(read.txtfont, "Mono");
+        assert_eq!(read.nodefont, "Sans");
+        assert_eq!(round_trip(&read), read);
+    }
+
+    #[test]
+    fn lazy_bodies_match() {
+        for path in ["Feather-Nodes-example.fnx", "Feather-content-example.fnx"] {
+            let eager = FeatherStruct::read(path.to_string(), false).unwrap();
+            let lazy = FeatherStruct::read(path.to_string(), true).unwrap();
+            assert!(
+                lazy.struct_xml.node[0].text.text.get().is_none(),
+                "{}",
+                path
+            );
+            assert_eq!(eager, lazy, "{}", path);
+        }
+    }
+
+    // The file is gone before the bodies are read, that has to be an error and not a panic
+    #[test]
+    fn lazy_body_of_a_removed_file() {
+        let path = std::env::temp_dir().join(format!("lazy-{}.fnx", std::process::id()));
+        std::fs::copy("Feather-Nodes-example.fnx", &path).unwrap();
+        let lazy = FeatherStruct::read(path.to_string_lossy().to_string(), true).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(lazy.struct_xml.node[0].text.as_str().is_err());
+        assert!(feathernotes_to_xml(&lazy.struct_xml).is_err());
+    }
+
+    #[test]
+    fn trim_body_is_what_is_read() {
+        let mut root = FeatherStruct::new().struct_xml;
+        let bodies = ["<p>a</p>\n", "\n <p>b</p>\t\r\n", " <p>c</p> "];
+        for body in bodies {
+            root.node.push(Node::new("node", body));
+        }
+        let read = round_trip(&root);
+        for (node, body) in read.node.iter().zip(bodies) {
+            assert_eq!(node.text.as_str().unwrap(), trim_body(body));
+        }
+    }
+
+    #[test]
+    fn escaped_text_round_trip() {
+        let mut root = FeatherStruct::new().struct_xml;
+        let mut node = Node::new(
+            "Fish & \"chips\" <3",
+            "<p>a &amp; b, <span>&lt;T&gt;</span> <a href=\"https://x.org/?a=1&amp;b=2\">x</a>\r\n</p>",
+        );
+        node.tag = Some("one, two\tthree\n".to_string());
+        node.attributes
+            .push(("geometry".to_string(), "1 & 2".to_string()));
+        node.node.push(Node::new("child", "<p>&amp;amp;</p>"));
+        root.node.push(node);
+
+        assert_eq!(round_trip(&root), root);
+        assert!(feathernotes_to_xml(&root)
+            .unwrap()
+            .contains("&lt;p>a &amp;amp; b"));
+    }
+
+    #[test]
+    fn cdata_end_in_text_round_trip() {
+        let mut root = FeatherStruct::new().struct_xml;
+        root.node.push(Node::new("a ]]> b", "<p>x[1[2]]>y</p>]]>"));
+
+        let xml = feathernotes_to_xml(&root).unwrap();
+        assert!(!xml.contains("]]>"));
+        assert_eq!(round_trip(&root), root);
+    }
+
+    #[test]
+    fn unknown_elements_keep_their_place() {
+        let xml = "<?xml version='1.0' encoding='UTF-8'?>\n\
+                   <feathernotes txtfont=\"Mono\" nodefont=\"Sans\">\
+                   <first/><node name=\"a\"><x>1</x><node name=\"b\"/><y/>\
+                   <node name=\"c\"/></node><last/></feathernotes>\n";
+        let read = feathernotes_from_xml(xml).unwrap();
+        assert_eq!(
+            read.elements,
+            [(0, "<first/>".into()), (1, "<last/>".into())]
+        );
+        assert_eq!(
+            read.node[0].elements,
+            [(0, "<x>1</x>".into()), (1, "<y/>".into())]
+        );
+
+        let written = feathernotes_to_xml(&read).unwrap();
+        let order: Vec<_> = [
+            "<first/>", "\"a\"", "<x>", "\"b\"", "<y/>", "\"c\"", "<last/>",
+        ]
+        .iter()
+        .map(|x| written.find(x).unwrap())
+        .collect();
+        assert!(order.windows(2).all(|x| x[0] < x[1]), "{}", written);
+        assert_eq!(round_trip(&read), read);
     }
-    Err(Box::new(MyError(String::new())))
 }
@@ -1,27 +1,47 @@
 use serde::{Deserialize, Serialize};
-use serde_json::Value;
-use std::error::*;
-use std::fmt;
-use std::io::Write;
-use std::path;
-
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use crate::attachments::{fix_attachment_links_md, AttachmentOptions};
+use crate::auth::redact_token;
+use crate::converter::{new_converter, ConverterKind};
+use crate::error::BridgeError;
+use crate::math::{render_math_images_md, MathMode};
 use crate::pandoc::fix_embedding_files_md;
 use crate::pandoc::repair_md_katex;
 use crate::pandoc::write_debug_file;
-
-// https://stackoverflow.com/questions/51550167/how-to-manually-return-a-result-boxdyn-error
-#[derive(Debug)]
-struct MyError(String);
-
-impl fmt::Display for MyError {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}", self.0)
+use crate::tags::{add_tag_line, TagMode};
+use crate::todo::add_due_header;
+use crate::util::{cache_dir, create_private_dir};
+
+// The most joplin gives in one page, its default is 10
+const PAGE_LIMIT: usize = 100;
+
+// A fixed order keeps pages from shifting and the written file the same between runs
+const LIST_ORDER: &str = "&order_by=title&order_dir=ASC";
+
+// 403 is a token joplin doesn't know, other failures are the api's
+fn check_response(
+    resp: reqwest::blocking::Response,
+    what: &str,
+) -> Result<reqwest::blocking::Response, BridgeError> {
+    let status = resp.status();
+    if status == reqwest::StatusCode::FORBIDDEN || status == reqwest::StatusCode::UNAUTHORIZED {
+        return Err(BridgeError::Auth(format!("{}: {}", what, status)));
+    }
+    if !status.is_success() {
+        return Err(BridgeError::Joplin(format!("{}: {}", what, status)));
     }
+    Ok(resp)
 }
 
-impl Error for MyError {}
-
-//
+fn json_str(v: &Value, field: &str) -> Result<String, BridgeError> {
+    match v[field].as_str() {
+        Some(x) => Ok(x.to_string()),
+        None => Err(BridgeError::Joplin(format!("No {} in the answer", field))),
+    }
+}
 
 // The same as Folders, but to avoid confusing
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -29,6 +49,14 @@ pub struct NotesArray {
     pub id: String,
     pub parent_id: String,
     pub title: String,
+    #[serde(default)]
+    pub updated_time: i64,
+    #[serde(default)]
+    pub is_todo: i64,
+    #[serde(default)]
+    pub todo_completed: i64, // When it was ticked, 0 if it isn't
+    #[serde(default)]
+    pub todo_due: i64,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -42,6 +70,8 @@ pub struct FoldersArray {
     pub id: String,
     pub parent_id: String,
     pub title: String,
+    #[serde(default)]
+    pub updated_time: i64,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -50,10 +80,90 @@ pub struct FoldersGet {
     pub has_more: bool,
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TagsArray {
+    pub id: String,
+    pub title: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TagsGet {
+    pub items: Vec<TagsArray>,
+    pub has_more: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ResourceInfo {
+    pub id: String,
+    #[serde(default)]
+    pub title: String,
+    #[serde(default)]
+    pub mime: String,
+    #[serde(default)]
+    pub file_extension: String,
+    #[serde(default)]
+    pub size: i64,
+    #[serde(default)]
+    pub updated_time: i64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ResourcesGet {
+    pub items: Vec<ResourceInfo>,
+    pub has_more: bool,
+}
+
+impl ResourceInfo {
+    // The same name joplin uses in its own resources directory
+    pub fn file_name(&self) -> String {
+        if self.file_extension.is_empty() {
+            self.id.clone()
+        } else {
+            format!("{}.{}", self.id, self.file_extension)
+        }
+    }
+
+    // In the cache a changed resource gets a new name, the old file isn't used for it anymore
+    pub fn cache_file_name(&self) -> String {
+        let name = format!("{}_{}", self.id, self.updated_time);
+        if self.file_extension.is_empty() {
+            name
+        } else {
+            format!("{}.{}", name, self.file_extension)
+        }
+    }
+}
+
+// Downloaded resources land here, so the same ones are not fetched on every run
+pub fn resource_cache_dir() -> PathBuf {
+    cache_dir("resources")
+}
+
+// JOPLIN_RESOURCE_PATH can point to a local joplin profile, it's used before downloading
+pub fn resource_dirs() -> Vec<PathBuf> {
+    let mut dirs = Vec::new();
+    if let Ok(x) = std::env::var("JOPLIN_RESOURCE_PATH") {
+        debug!("Using custom joplin resource path: {}", x);
+        dirs.push(PathBuf::from(x));
+    }
+    dirs.push(resource_cache_dir());
+    dirs
+}
+
 pub struct JoplinData {
+    pub base_url: String, // Without the trailing slash
+    pub attachments: AttachmentOptions,
+    pub math: MathMode,
+    pub converter: ConverterKind,
+    pub tag_mode: TagMode,
+    pub jobs: usize, // Notes fetched and converted at the same time
     pub token_string: String,
     pub dir_list: Vec<FoldersArray>, // We can't request only specific dirs so we need to do this, so save it for later
     pub notes_list: Vec<NotesArray>, // Searching doesn't work, some weird token error, but it's there
+    pub tags: Vec<TagsArray>,        // Only there after load_tags
+    pub note_tags: HashMap<String, Vec<String>>, // Note id to the titles of its tags
+    pub note_bodies: HashMap<String, String>, // Only there after load_note_bodies
+    index: FolderIndex,              // Kept in line with the lists by the methods that change them
 }
 
 #[derive(Debug)]
@@ -62,14 +172,53 @@ pub struct MinimumFolder {
     pub id: String,
 }
 
+// Positions in dir_list and notes_list by id and by parent, so paths and children don't need
+// a request or a search through the lists
+#[derive(Debug, Default)]
+struct FolderIndex {
+    folders: HashMap<String, usize>,
+    notes: HashMap<String, usize>,
+    child_folders: HashMap<String, Vec<usize>>,
+    child_notes: HashMap<String, Vec<usize>>,
+}
+
+impl FolderIndex {
+    fn build(folders: &[FoldersArray], notes: &[NotesArray]) -> FolderIndex {
+        let mut index = FolderIndex::default();
+        for (position, folder) in folders.iter().enumerate() {
+            index.add_folder(position, folder);
+        }
+        for (position, note) in notes.iter().enumerate() {
+            index.add_note(position, note);
+        }
+        index
+    }
+
+    fn add_folder(&mut self, position: usize, folder: &FoldersArray) {
+        self.folders.insert(folder.id.clone(), position);
+        self.child_folders
+            .entry(folder.parent_id.clone())
+            .or_default()
+            .push(position);
+    }
+
+    fn add_note(&mut self, position: usize, note: &NotesArray) {
+        self.notes.insert(note.id.clone(), position);
+        self.child_notes
+            .entry(note.parent_id.clone())
+            .or_default()
+            .push(position);
+    }
+}
+
 impl JoplinData {
-    pub fn ping(&self) -> Result<(), Box<dyn Error>> {
+    pub fn ping(&self) -> Result<(), BridgeError> {
         // https://stackoverflow.com/questions/54159232/best-practice-to-return-a-result-impl-error-and-not-a-result-str-in-rus
-        let resp = reqwest::blocking::get("http://127.0.0.1:41184/ping");
+        let resp = reqwest::blocking::get(format!("{}/ping", self.base_url));
         match resp {
             Ok(x) => {
                 debug!("Ping succesfull: {:#?}", x);
-                if x.text().unwrap() == "JoplinClipperServer" {
+                if x.text()? == "JoplinClipperServer" {
                     info!("Ping worked");
                 } else {
                     warn!("Ping failure?");
@@ -77,22 +226,35 @@ impl JoplinData {
             }
             Err(x) => {
                 error!("Failed to ping: {}", x);
-                return Err(Box::new(MyError("Ping failed".into())));
+                return Err(x.into());
             }
         }
         Result::Ok(())
     }
 
-    pub fn new(provided_token: String) -> Result<JoplinData, Box<dyn Error>> {
+    pub fn new(provided_token: String, base_url: &str) -> Result<JoplinData, BridgeError> {
         let mut new = JoplinData {
+            base_url: base_url.trim_end_matches('/').to_string(),
+            attachments: AttachmentOptions::default(),
+            math: MathMode::Tex,
+            converter: ConverterKind::Native,
+            tag_mode: TagMode::None,
+            jobs: 1,
             token_string: format!("?token={}", provided_token),
             dir_list: Vec::new(),
             notes_list: Vec::new(),
+            tags: Vec::new(),
+            note_tags: HashMap::new(),
+            note_bodies: HashMap::new(),
+            index: FolderIndex::default(),
         };
-        new.ping().unwrap();
+        new.ping()?;
 
-        let mut request = format!("http://127.0.0.1:41184/{}{}", "/folders", &new.token_string);
-        let responses = &new.request_pages_iterate(&mut request).unwrap();
+        let mut request = new.request_url(
+            "folders",
+            &format!("&fields=id,parent_id,title,updated_time{}", LIST_ORDER),
+        );
+        let responses = &new.request_pages_iterate(&mut request)?;
 
         for response in responses {
             let mut page: FoldersGet = serde_json::from_str(response.as_str())?;
@@ -102,8 +264,14 @@ impl JoplinData {
         debug!("Got all folders: {:#?}", new.dir_list);
         debug!("There are {} folders in total", new.dir_list.len());
 
-        let mut request = format!("http://127.0.0.1:41184/{}{}", "/notes", &new.token_string);
-        let responses = &new.request_pages_iterate(&mut request).unwrap();
+        let mut request = new.request_url(
+            "notes",
+            &format!(
+                "&fields=id,parent_id,title,updated_time,is_todo,todo_completed,todo_due{}",
+                LIST_ORDER
+            ),
+        );
+        let responses = &new.request_pages_iterate(&mut request)?;
 
         for response in responses {
             let mut page: NotesGet = serde_json::from_str(response.as_str())?;
@@ -111,34 +279,134 @@ impl JoplinData {
         }
 
         debug!("There are {} notes in total", new.notes_list.len());
+        new.reindex();
 
         Result::Ok(new)
     }
 
+    // Every tag asks joplin for its notes, so this is only done when tags are wanted
+    pub fn load_tags(&mut self) -> Result<(), BridgeError> {
+        let mut request = self.request_url("tags", "&fields=id,title");
+        for response in self.request_pages_iterate(&mut request)? {
+            let mut page: TagsGet = serde_json::from_str(response.as_str())?;
+            self.tags.append(&mut page.items);
+        }
+
+        for tag in &self.tags {
+            let mut request = self.request_url(&format!("tags/{}/notes", tag.id), "&fields=id");
+            for response in self.request_pages_iterate(&mut request)? {
+                let v: Value = serde_json::from_str(response.as_str())?;
+                for note in v["items"].as_array().into_iter().flatten() {
+                    if let Some(id) = note["id"].as_str() {
+                        self.note_tags
+                            .entry(id.to_string())
+                            .or_default()
+                            .push(tag.title.clone());
+                    }
+                }
+            }
+        }
+
+        debug!(
+            "There are {} tags on {} notes",
+            self.tags.len(),
+            self.note_tags.len()
+        );
+        Ok(())
+    }
+
+    // Positions move when something is removed, so then it's all built again
+    fn reindex(&mut self) {
+        self.index = FolderIndex::build(&self.dir_list, &self.notes_list);
+    }
+
+    pub fn folder(&self, folder_id: &str) -> Option<&FoldersArray> {
+        self.index
+            .folders
+            .get(folder_id)
+            .map(|x| &self.dir_list[*x])
+    }
+
+    pub fn note(&self, note_id: &str) -> Option<&NotesArray> {
+        self.index.notes.get(note_id).map(|x| &self.notes_list[*x])
+    }
+
+    // In the order joplin listed them
+    pub fn child_folders(&self, parent_id: &str) -> impl Iterator<Item = &FoldersArray> {
+        self.index
+            .child_folders
+            .get(parent_id)
+            .into_iter()
+            .flatten()
+            .map(|x| &self.dir_list[*x])
+    }
+
+    pub fn child_notes(&self, parent_id: &str) -> impl Iterator<Item = &NotesArray> {
+        self.index
+            .child_notes
+            .get(parent_id)
+            .into_iter()
+            .flatten()
+            .map(|x| &self.notes_list[*x])
+    }
+
+    // Bodies come with the listing, a hundred at a time, instead of a request per note. Worth it
+    // when most notes get converted
+    pub fn load_note_bodies(&mut self) -> Result<(), BridgeError> {
+        let mut request = self.request_url("notes", &format!("&fields=id,body{}", LIST_ORDER));
+        for response in self.request_pages_iterate(&mut request)? {
+            let v: Value = serde_json::from_str(response.as_str())?;
+            for note in v["items"].as_array().into_iter().flatten() {
+                self.note_bodies
+                    .insert(json_str(note, "id")?, json_str(note, "body")?);
+            }
+        }
+        debug!("Got the bodies of {} notes", self.note_bodies.len());
+        Ok(())
+    }
+
+    pub fn tags_of_note(&self, note_id: &str) -> Vec<String> {
+        let mut tags = self.note_tags.get(note_id).cloned().unwrap_or_default();
+        tags.sort();
+        tags
+    }
+
+    pub fn redact(&self, text: &str) -> String {
+        redact_token(text, self.token_string.trim_start_matches("?token="))
+    }
+
+    // Query is added after the token, like "&fields=body"
+    pub fn request_url(&self, path: &str, query: &str) -> String {
+        format!(
+            "{}/{}{}{}",
+            self.base_url,
+            path.trim_matches('/'),
+            self.token_string,
+            query
+        )
+    }
+
     pub fn add_page(&self, page: i32, request: &mut String) -> String {
-        format!("{}&page={}", request, page)
+        format!("{}&page={}&limit={}", request, page, PAGE_LIMIT)
     }
 
-    pub fn request_pages_iterate(
-        &self,
-        request: &mut String,
-    ) -> Result<Vec<String>, Box<dyn Error>> {
+    pub fn request_pages_iterate(&self, request: &mut String) -> Result<Vec<String>, BridgeError> {
         let mut page = 1;
         let request_page = self.add_page(page, request);
-        debug!("Request URL: {}", request);
+        debug!("Request URL: {}", self.redact(request));
         let mut responses: Vec<String> = Vec::new();
-        let mut resp = reqwest::blocking::get(request_page)?;
-        let mut text = resp.text().unwrap();
+        let mut resp = check_response(reqwest::blocking::get(request_page)?, "Listing")?;
+        let mut text = resp.text()?;
         debug!("Text from first run: {:#?}", text);
         responses.push(text.clone());
         loop {
             let v: Value = serde_json::from_str(text.as_str())?;
-            if v["has_more"].as_bool().unwrap() {
+            if v["has_more"].as_bool().unwrap_or(false) {
                 page += 1;
                 debug!("Running for page: {}", page);
                 let request_page = self.add_page(page, request);
-                resp = reqwest::blocking::get(request_page)?;
-                text = resp.text().unwrap();
+                resp = check_response(reqwest::blocking::get(request_page)?, "Listing")?;
+                text = resp.text()?;
                 responses.push(text.clone());
             } else {
                 debug!("There are no more pages. Last page was: {}", page);
@@ -153,23 +421,17 @@ impl JoplinData {
         folder_storage: &mut Vec<FoldersArray>,
         root_id: String,
     ) {
-        for folder in &self.dir_list {
-            if folder.parent_id == root_id {
-                folder_storage.push(folder.to_owned().clone());
-                self.look_for_children_folders(folder_storage, folder.id.clone());
-            }
+        for folder in self.child_folders(&root_id) {
+            folder_storage.push(folder.clone());
+            self.look_for_children_folders(folder_storage, folder.id.clone());
         }
     }
 
-    pub fn lookup_folder(&self, folder_id: String) -> Result<Vec<FoldersArray>, Box<dyn Error>> {
+    pub fn lookup_folder(&self, folder_id: String) -> Result<Vec<FoldersArray>, BridgeError> {
         // We also push here the root that was asked for
         let mut folders_children: Vec<FoldersArray> = Vec::new();
 
-        let root_index_option = self.dir_list.iter().position(|x| x.id == folder_id);
-
-        if root_index_option.is_some() {
-            let index = root_index_option.unwrap();
-            let root = &self.dir_list[index];
+        if let Some(root) = self.folder(&folder_id) {
             folders_children.push(root.to_owned());
             self.look_for_children_folders(&mut folders_children, root.id.clone());
 
@@ -180,83 +442,194 @@ impl JoplinData {
                 folders_children.len()
             );
         } else {
-            return Err(Box::new(MyError(
-                "Couldn't found requested folder name".into(),
+            return Err(BridgeError::Joplin(format!(
+                "Couldn't find the folder {}",
+                folder_id
             )));
         }
 
         Result::Ok(folders_children)
     }
 
-    pub fn get_notes_of_folder(
-        &mut self,
-        folder_id: &str,
-    ) -> Result<Vec<NotesArray>, Box<dyn Error>> {
-        let mut notes: Vec<NotesArray> = Vec::new();
-        for note in &self.notes_list {
-            if note.parent_id == folder_id {
-                notes.push(note.clone());
-            }
-        }
+    pub fn get_notes_of_folder(&mut self, folder_id: &str) -> Result<Vec<NotesArray>, BridgeError> {
+        let notes: Vec<NotesArray> = self.child_notes(folder_id).cloned().collect();
 
         debug!("Found {} notes", notes.len());
 
         Ok(notes)
     }
 
-    pub fn get_note_body(&self, note_id: &str) -> Result<String, Box<dyn Error>> {
-        let request = format!(
-            "http://127.0.0.1:41184/notes/{}{}&fields=body",
-            note_id, self.token_string
-        );
+    // The body as FeatherNotes html, with the chosen converter
+    pub fn get_note_html(&self, note_id: &str) -> Result<String, BridgeError> {
+        let mut body_md = self.get_note_body(note_id)?;
+        if let Some(note) = self.note(note_id) {
+            body_md = add_due_header(body_md, note);
+        }
+        if self.tag_mode == TagMode::Line {
+            body_md = add_tag_line(body_md, &self.tags_of_note(note_id));
+        }
+        new_converter(self.converter, self.math).md_to_html(body_md)
+    }
 
-        debug!("get_note_body request: {}", request);
+    pub fn get_note_body(&self, note_id: &str) -> Result<String, BridgeError> {
+        let mut str = match self.note_bodies.get(note_id) {
+            Some(x) => x.clone(),
+            None => {
+                let request = self.request_url(&format!("notes/{}", note_id), "&fields=body");
+                debug!("get_note_body request: {}", self.redact(&request));
 
-        let resp = reqwest::blocking::get(request)?;
-        let v: Value = serde_json::from_str(&resp.text().unwrap())?;
+                let resp = check_response(reqwest::blocking::get(request)?, "Getting a note")?;
+                let v: Value = serde_json::from_str(&resp.text()?)?;
+                json_str(&v, "body")?
+            }
+        };
 
-        let mut str = v["body"].as_str().unwrap().to_string();
+        // Resources are only linked as :/id, without one there is nothing to ask for
+        let resources = if str.contains(":/") {
+            self.get_note_resources(note_id)?
+        } else {
+            Vec::new()
+        };
+        let mut files: HashMap<String, PathBuf> = HashMap::new();
+        for resource in &resources {
+            files.insert(resource.id.clone(), self.download_resource(resource)?);
+        }
 
         str = repair_md_katex(str);
-        str = fix_embedding_files_md(str);
+        if self.math == MathMode::Image {
+            str = render_math_images_md(str);
+        }
+        str = fix_embedding_files_md(str, &resources, &files);
+        str = fix_attachment_links_md(str, &resources, &files, &self.attachments)?;
 
         write_debug_file("", str.to_string(), ".md");
 
         Ok(str)
     }
 
-    // http://127.0.0.1:41184/folders/12b29e02391b48a29cf730ddee8b01ff?token=f7367f972d8d645a85c1ede0a9daabb5e1a43637570437b9289ff4cba45b6066c7a0072eabd70eab7e7d471338f5786d3b425e108f9b6149b60e0f105ab2525e
-    pub fn get_path_folder(&self, folder_id: &str) -> Result<Vec<MinimumFolder>, Box<dyn Error>> {
-        let mut path_not_inversed: Vec<MinimumFolder> = Vec::new();
-        let mut id_to_look_for = folder_id.to_string();
-        loop {
-            let request = format!(
-                "http://127.0.0.1:41184/folders/{}{}",
-                id_to_look_for, self.token_string
-            );
+    pub fn get_note_resources(&self, note_id: &str) -> Result<Vec<ResourceInfo>, BridgeError> {
+        let mut request = self.request_url(
+            &format!("notes/{}/resources", note_id),
+            "&fields=id,title,mime,file_extension,size,updated_time",
+        );
+        let responses = self.request_pages_iterate(&mut request)?;
 
-            debug!("get_path_folder request: {}", request);
+        let mut resources: Vec<ResourceInfo> = Vec::new();
+        for response in responses {
+            let mut page: ResourcesGet = serde_json::from_str(response.as_str())?;
+            resources.append(&mut page.items);
+        }
+        debug!("Note {} has {} resources", note_id, resources.len());
 
-            let resp = reqwest::blocking::get(request)?;
-            let v: Value = serde_json::from_str(&resp.text().unwrap())?;
+        Ok(resources)
+    }
 
-            let parent_id = v["parent_id"].as_str().unwrap();
+    // Returns where the file is, it's only downloaded if no resource dir has it already
+    pub fn download_resource(&self, resource: &ResourceInfo) -> Result<PathBuf, BridgeError> {
+        // A local joplin profile has the current version under the plain name, the cache one
+        // with its updated_time
+        let dir = resource_cache_dir();
+        let file_name = resource.cache_file_name();
+        let mut paths: Vec<PathBuf> = resource_dirs()
+            .into_iter()
+            .filter(|x| x != &dir)
+            .map(|x| x.join(resource.file_name()))
+            .collect();
+        paths.push(dir.join(&file_name));
+        for path in paths {
+            if path.exists() {
+                debug!("Resource {} is already at {}", resource.id, path.display());
+                return Ok(path);
+            }
+        }
 
+        let request = self.request_url(&format!("resources/{}/file", resource.id), "");
+        debug!("download_resource request: {}", self.redact(&request));
+
+        let resp = check_response(
+            reqwest::blocking::get(request)?,
+            &format!("Downloading resource {}", resource.id),
+        )?;
+        let bytes = resp.bytes()?;
+
+        // Another worker may be looking for it meanwhile, it only gets the name once it's complete
+        create_private_dir(&dir)?;
+        let path = dir.join(&file_name);
+        let tmp_path = dir.join(format!(
+            ".{}.{}.{:?}.tmp",
+            file_name,
+            std::process::id(),
+            std::thread::current().id()
+        ));
+        std::fs::write(&tmp_path, &bytes)?;
+        std::fs::rename(&tmp_path, &path)?;
+        info!("Downloaded resource {} ({})", resource.title, resource.mime);
+
+        Ok(path)
+    }
+
+    pub fn upload_resource(
+        &self,
+        title: &str,
+        file_name: &str,
+        bytes: Vec<u8>,
+    ) -> Result<String, BridgeError> {
+        let request = self.request_url("resources", "");
+        debug!("upload_resource request: {}", self.redact(&request));
+
+        let form = reqwest::blocking::multipart::Form::new()
+            .part(
+                "data",
+                reqwest::blocking::multipart::Part::bytes(bytes).file_name(file_name.to_string()),
+            )
+            .text("props", json!({ "title": title }).to_string());
+
+        let resp = reqwest::blocking::Client::new()
+            .post(request)
+            .multipart(form)
+            .send()?;
+        let resp = check_response(resp, &format!("Uploading resource {}", title))?;
+
+        let v: Value = serde_json::from_str(&resp.text()?)?;
+        match v["id"].as_str() {
+            Some(id) => {
+                info!("Uploaded {} as resource {}", title, id);
+                Ok(id.to_string())
+            }
+            None => Err(BridgeError::Joplin(format!(
+                "No id for the uploaded resource {}",
+                title
+            ))),
+        }
+    }
+
+    // From the root down to folder_id. Folders come from dir_list, only one that isn't there,
+    // like one made in joplin after we listed them, is asked for
+    pub fn get_path_folder(&self, folder_id: &str) -> Result<Vec<MinimumFolder>, BridgeError> {
+        let mut path_not_inversed: Vec<MinimumFolder> = Vec::new();
+        let mut id_to_look_for = folder_id.to_string();
+        loop {
+            let (folder, parent_id) = match self.folder(&id_to_look_for) {
+                Some(x) => (
+                    MinimumFolder {
+                        title: x.title.clone(),
+                        id: x.id.clone(),
+                    },
+                    x.parent_id.clone(),
+                ),
+                None => self.fetch_folder(&id_to_look_for)?,
+            };
+            path_not_inversed.push(folder);
             if parent_id.is_empty() {
-                let folder = MinimumFolder {
-                    title: v["title"].as_str().unwrap().to_string(),
-                    id: v["id"].as_str().unwrap().to_string(),
-                };
-                path_not_inversed.push(folder);
                 break;
-            } else {
-                let folder = MinimumFolder {
-                    title: v["title"].as_str().unwrap().to_string(),
-                    id: v["id"].as_str().unwrap().to_string(),
-                };
-                path_not_inversed.push(folder);
-                id_to_look_for = parent_id.to_string();
             }
+            if path_not_inversed.iter().any(|x| x.id == parent_id) {
+                return Err(BridgeError::Joplin(format!(
+                    "Folder {} is inside itself",
+                    parent_id
+                )));
+            }
+            id_to_look_for = parent_id;
         }
 
         let path_inversed: Vec<MinimumFolder> = path_not_inversed.into_iter().rev().collect();
@@ -265,16 +638,170 @@ impl JoplinData {
         Ok(path_inversed)
     }
 
-    pub fn get_parent_of_note(&self, note_id: String) -> Result<String, Box<dyn Error>> {
-        let request = format!(
-            "http://127.0.0.1:41184/notes/{}/{}",
-            note_id, self.token_string
-        );
-        debug!("get_parent_of_note request: {}", request);
+    // http://127.0.0.1:41184/folders/12b29e02391b48a29cf730ddee8b01ff?token=<token>
+    fn fetch_folder(&self, folder_id: &str) -> Result<(MinimumFolder, String), BridgeError> {
+        let request = self.request_url(&format!("folders/{}", folder_id), "");
+        debug!("fetch_folder request: {}", self.redact(&request));
+
+        let resp = check_response(reqwest::blocking::get(request)?, "Getting a folder")?;
+        let v: Value = serde_json::from_str(&resp.text()?)?;
+
+        let folder = MinimumFolder {
+            title: json_str(&v, "title")?,
+            id: json_str(&v, "id")?,
+        };
+        Ok((folder, json_str(&v, "parent_id")?))
+    }
+
+    pub fn get_parent_of_note(&self, note_id: String) -> Result<String, BridgeError> {
+        if let Some(note) = self.note(&note_id) {
+            return Ok(note.parent_id.clone());
+        }
+        let request = self.request_url(&format!("notes/{}", note_id), "");
+        debug!("get_parent_of_note request: {}", self.redact(&request));
+
+        let resp = check_response(reqwest::blocking::get(request)?, "Getting a note")?;
+        let v: Value = serde_json::from_str(&resp.text()?)?;
 
-        let resp = reqwest::blocking::get(request)?;
-        let v: Value = serde_json::from_str(&resp.text().unwrap())?;
+        json_str(&v, "parent_id")
+    }
+
+    // Joplin returns the whole created item, we only care about its id
+    fn post_item(&self, item_type: &str, item: Value) -> Result<String, BridgeError> {
+        let request = self.request_url(item_type, "");
+        debug!("post_item request: {}", self.redact(&request));
+
+        let resp = reqwest::blocking::Client::new()
+            .post(request)
+            .json(&item)
+            .send()?;
+        let resp = check_response(resp, &format!("Creating {}", item_type))?;
+
+        let v: Value = serde_json::from_str(&resp.text()?)?;
+        json_str(&v, "id")
+    }
 
-        Ok(v["parent_id"].as_str().unwrap().to_string())
+    // Empty parent_id means root
+    pub fn create_folder(&mut self, title: &str, parent_id: &str) -> Result<String, BridgeError> {
+        let id = self.post_item("folders", json!({ "title": title, "parent_id": parent_id }))?;
+        debug!("Created folder {} with id {}", title, id);
+
+        let folder = FoldersArray {
+            id: id.clone(),
+            parent_id: parent_id.to_string(),
+            title: title.to_string(),
+            updated_time: 0,
+        };
+        self.index.add_folder(self.dir_list.len(), &folder);
+        self.dir_list.push(folder);
+        Ok(id)
+    }
+
+    // item has the title, body and to-do fields, see feather_note_to_joplin
+    pub fn create_note(&mut self, mut item: Value, parent_id: &str) -> Result<String, BridgeError> {
+        // Without parent_id Joplin puts the note into the currently selected notebook
+        if !parent_id.is_empty() {
+            item["parent_id"] = json!(parent_id);
+        }
+        let id = self.post_item("notes", item.clone())?;
+        let title = item["title"].as_str().unwrap_or_default();
+        debug!("Created note {} with id {}", title, id);
+
+        let note = NotesArray {
+            id: id.clone(),
+            parent_id: parent_id.to_string(),
+            title: title.to_string(),
+            updated_time: 0,
+            is_todo: item["is_todo"].as_i64().unwrap_or_default(),
+            todo_completed: item["todo_completed"].as_i64().unwrap_or_default(),
+            todo_due: item["todo_due"].as_i64().unwrap_or_default(),
+        };
+        self.index.add_note(self.notes_list.len(), &note);
+        self.notes_list.push(note);
+        Ok(id)
+    }
+
+    // Returns the new updated_time, the local lists are kept in line with joplin
+    pub fn update_item(
+        &mut self,
+        item_type: &str,
+        id: &str,
+        item: Value,
+    ) -> Result<i64, BridgeError> {
+        let request = self.request_url(&format!("{}/{}", item_type, id), "");
+        debug!("update_item request: {}", self.redact(&request));
+
+        let resp = reqwest::blocking::Client::new()
+            .put(request)
+            .json(&item)
+            .send()?;
+        check_response(resp, &format!("Updating {} {}", item_type, id))?;
+
+        let updated_time = self.get_updated_time(item_type, id)?;
+        self.note_bodies.remove(id);
+        let title = item["title"].as_str();
+        let parent_id = item["parent_id"].as_str();
+        if item_type == "folders" {
+            if let Some(folder) = self.dir_list.iter_mut().find(|x| x.id == id) {
+                folder.title = title.unwrap_or(&folder.title).to_string();
+                folder.parent_id = parent_id.unwrap_or(&folder.parent_id).to_string();
+                folder.updated_time = updated_time;
+            }
+        } else if let Some(note) = self.notes_list.iter_mut().find(|x| x.id == id) {
+            note.title = title.unwrap_or(&note.title).to_string();
+            note.parent_id = parent_id.unwrap_or(&note.parent_id).to_string();
+            note.updated_time = updated_time;
+            note.is_todo = item["is_todo"].as_i64().unwrap_or(note.is_todo);
+            note.todo_completed = item["todo_completed"]
+                .as_i64()
+                .unwrap_or(note.todo_completed);
+            note.todo_due = item["todo_due"].as_i64().unwrap_or(note.todo_due);
+        }
+        if parent_id.is_some() {
+            self.reindex();
+        }
+
+        Ok(updated_time)
+    }
+
+    // Joplin moves deleted items to its trash. Already gone is fine too
+    pub fn delete_item(&mut self, item_type: &str, id: &str) -> Result<(), BridgeError> {
+        let request = self.request_url(&format!("{}/{}", item_type, id), "");
+        debug!("delete_item request: {}", self.redact(&request));
+
+        let resp = reqwest::blocking::Client::new().delete(request).send()?;
+
+        if resp.status() != reqwest::StatusCode::NOT_FOUND {
+            check_response(resp, &format!("Deleting {} {}", item_type, id))?;
+        }
+
+        if item_type == "folders" {
+            self.dir_list.retain(|x| x.id != id);
+        } else {
+            self.notes_list.retain(|x| x.id != id);
+            self.note_bodies.remove(id);
+        }
+        self.reindex();
+        Ok(())
+    }
+
+    // Sync needs the time Joplin gave an item after we wrote it
+    pub fn get_updated_time(&self, item_type: &str, id: &str) -> Result<i64, BridgeError> {
+        let request = self.request_url(&format!("{}/{}", item_type, id), "&fields=updated_time");
+        debug!("get_updated_time request: {}", self.redact(&request));
+
+        let resp = check_response(
+            reqwest::blocking::get(request)?,
+            &format!("Getting {} {}", item_type, id),
+        )?;
+        let v: Value = serde_json::from_str(&resp.text()?)?;
+
+        match v["updated_time"].as_i64() {
+            Some(x) => Ok(x),
+            None => Err(BridgeError::Joplin(format!(
+                "No updated_time for {} {}",
+                item_type, id
+            ))),
+        }
     }
 }
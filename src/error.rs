@@ -0,0 +1,94 @@
+use std::error::Error;
+use std::fmt;
+
+// Everything that stops the bridge, main turns the kind into the exit code
+#[derive(Debug)]
+pub enum BridgeError {
+    Network(reqwest::Error), // Joplin can't be reached
+    Auth(String),            // No token, or Joplin doesn't take it
+    Joplin(String),          // Joplin answered, but not what was asked for
+    Xml(String),             // The feather file, or the node tree made from it
+    Conversion(String),      // Markdown to html and back
+    Io(std::io::Error),
+}
+
+impl BridgeError {
+    // 1 is left for panics, 2 is what clap uses for bad arguments
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            BridgeError::Network(_) => 3,
+            BridgeError::Auth(_) => 4,
+            BridgeError::Joplin(_) => 5,
+            BridgeError::Xml(_) => 6,
+            BridgeError::Conversion(_) => 7,
+            BridgeError::Io(_) => 8,
+        }
+    }
+}
+
+impl fmt::Display for BridgeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BridgeError::Network(x) => write!(f, "Couldn't reach Joplin: {}", x),
+            BridgeError::Auth(x) => write!(f, "Joplin authorisation failed: {}", x),
+            BridgeError::Joplin(x) => write!(f, "Joplin API error: {}", x),
+            BridgeError::Xml(x) => write!(f, "Feather file error: {}", x),
+            BridgeError::Conversion(x) => write!(f, "Conversion failed: {}", x),
+            BridgeError::Io(x) => write!(f, "File error: {}", x),
+        }
+    }
+}
+
+impl Error for BridgeError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            BridgeError::Network(x) => Some(x),
+            BridgeError::Io(x) => Some(x),
+            _ => None,
+        }
+    }
+}
+
+// reqwest puts the url in its message, and with it the token
+impl From<reqwest::Error> for BridgeError {
+    fn from(x: reqwest::Error) -> Self {
+        BridgeError::Network(x.without_url())
+    }
+}
+
+impl From<std::io::Error> for BridgeError {
+    fn from(x: std::io::Error) -> Self {
+        BridgeError::Io(x)
+    }
+}
+
+// Json only comes from Joplin, the sync state maps its own errors
+impl From<serde_json::Error> for BridgeError {
+    fn from(x: serde_json::Error) -> Self {
+        BridgeError::Joplin(format!("Unexpected answer: {}", x))
+    }
+}
+
+impl From<quick_xml::Error> for BridgeError {
+    fn from(x: quick_xml::Error) -> Self {
+        BridgeError::Xml(x.to_string())
+    }
+}
+
+impl From<quick_xml::events::attributes::AttrError> for BridgeError {
+    fn from(x: quick_xml::events::attributes::AttrError) -> Self {
+        BridgeError::Xml(x.to_string())
+    }
+}
+
+impl From<std::string::FromUtf8Error> for BridgeError {
+    fn from(x: std::string::FromUtf8Error) -> Self {
+        BridgeError::Xml(x.to_string())
+    }
+}
+
+impl From<base64::DecodeError> for BridgeError {
+    fn from(x: base64::DecodeError) -> Self {
+        BridgeError::Conversion(x.to_string())
+    }
+}
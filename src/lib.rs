@@ -0,0 +1,17 @@
+#[macro_use]
+extern crate log;
+
+pub mod attachments;
+pub mod auth;
+pub mod convert_logic;
+pub mod converter;
+pub mod data_types;
+pub mod error;
+pub mod math;
+pub mod native;
+pub mod pandoc;
+pub mod plan;
+pub mod sync;
+pub mod tags;
+pub mod todo;
+pub mod util;
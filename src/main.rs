@@ -3,22 +3,40 @@ extern crate log;
 
 use std::process::ExitCode;
 
-mod convert_logic;
-mod data_types;
-mod pandoc;
-
-use crate::convert_logic::overwrite_joplin_to_feathernotes;
-
-use data_types::feather::*;
-use data_types::joplin::*;
+use feather_joplin_bridge::attachments::{AttachmentMode, AttachmentOptions};
+use feather_joplin_bridge::auth;
+use feather_joplin_bridge::convert_logic::{
+    overwrite_feathernotes_to_joplin, overwrite_joplin_to_feathernotes,
+};
+use feather_joplin_bridge::converter::ConverterKind;
+use feather_joplin_bridge::data_types::feather::*;
+use feather_joplin_bridge::data_types::joplin::*;
+use feather_joplin_bridge::error::BridgeError;
+use feather_joplin_bridge::math::MathMode;
+use feather_joplin_bridge::plan::{Plan, PlanFormat};
+use feather_joplin_bridge::sync::{sync_joplin_and_feathernotes, ConflictStrategy, SyncOptions};
+use feather_joplin_bridge::tags::TagMode;
 
 use clap::Parser;
 
 #[derive(Parser, Debug)]
 #[command(author, version, about)]
 struct Args {
-    #[arg(short, long, help = "Joplin web clipper access token")]
-    token: String,
+    #[arg(
+        short,
+        long,
+        env = "JOPLIN_TOKEN",
+        hide_env_values = true,
+        help = "Joplin web clipper access token, if not given the saved one is used or Joplin is asked for a new one"
+    )]
+    token: Option<String>,
+    #[arg(
+        long,
+        env = "JOPLIN_URL",
+        help = "Joplin web clipper address, change it for another port or a tunnel",
+        default_value_t = String::from("http://127.0.0.1:41184")
+    )]
+    joplin_url: String,
     #[arg(
         short,
         long,
@@ -38,6 +56,104 @@ struct Args {
         help = "Overwrites / Writes a new feather file, converting notes from Joplin"
     )]
     overwrite_feather: bool,
+    #[arg(
+        long,
+        help = "Writes notes from feather file to Joplin, inside joplin-folder-id or at root",
+        requires = "feather_file",
+        conflicts_with = "overwrite_feather"
+    )]
+    overwrite_joplin: bool,
+    #[arg(
+        long,
+        help = "Syncs changes both ways, remembering the last sync in a file next to output-file",
+        conflicts_with_all = ["overwrite_feather", "overwrite_joplin"]
+    )]
+    sync: bool,
+    #[arg(
+        long,
+        value_enum,
+        help = "What to do with notes changed both in Joplin and Feather since the last sync",
+        default_value_t = ConflictStrategy::KeepBoth
+    )]
+    conflict: ConflictStrategy,
+    #[arg(
+        long,
+        help = "Sync moves nodes deleted in Joplin into a root node with this name, instead of removing them"
+    )]
+    trash_node: Option<String>,
+    #[arg(
+        long,
+        help = "Only prints what would be done, nothing gets written to Joplin or to files"
+    )]
+    dry_run: bool,
+    #[arg(
+        long,
+        value_enum,
+        help = "What to do with non image attachments of Joplin notes",
+        default_value_t = AttachmentMode::Export
+    )]
+    attachments: AttachmentMode,
+    #[arg(
+        long,
+        help = "Biggest attachment in bytes that gets embedded with --attachments embed",
+        default_value_t = 1024 * 1024
+    )]
+    attachment_embed_limit: u64,
+    #[arg(
+        long,
+        value_enum,
+        help = "How formulas end up in FeatherNotes, image needs latex and dvisvgm, webtex needs internet",
+        default_value_t = MathMode::Tex
+    )]
+    math: MathMode,
+    #[arg(
+        long,
+        value_enum,
+        help = "How Joplin markdown becomes FeatherNotes html, pandoc needs the pandoc binary",
+        default_value_t = ConverterKind::Native
+    )]
+    converter: ConverterKind,
+    #[arg(
+        long,
+        value_enum,
+        help = "Where Joplin tags go in FeatherNotes, a line at the top of the body or the node tag. Tags are only fetched when set",
+        default_value_t = TagMode::None
+    )]
+    tags: TagMode,
+    #[arg(
+        long,
+        help = "Root node that gets a node per tag with links to its notes, made again every run"
+    )]
+    tags_tree: Option<String>,
+    #[arg(long, value_enum, help = "How to print the plan of --dry-run", default_value_t = PlanFormat::Tree)]
+    plan_format: PlanFormat,
+    #[arg(
+        long,
+        help = "How many timestamped backups of the old output file to keep next to it, 0 for none",
+        default_value_t = 5
+    )]
+    backups: usize,
+    #[arg(
+        long,
+        help = "Reads node bodies from feather-file only when they are needed, for very big files"
+    )]
+    lazy: bool,
+    #[arg(
+        long,
+        help = "How many notes are fetched and converted at the same time with --overwrite-feather",
+        default_value_t = 4,
+        value_parser = clap::value_parser!(u16).range(1..)
+    )]
+    jobs: u16,
+}
+
+fn modified_time_millis(path: &str) -> i64 {
+    std::fs::metadata(path)
+        .and_then(|x| x.modified())
+        .ok()
+        .and_then(|x| x.duration_since(std::time::UNIX_EPOCH).ok())
+        .map(|x| x.as_millis() as i64)
+        .unwrap_or_default()
 }
 
 //use crate::pandoc::{convert_md_to_html, write_debug_file};
@@ -50,25 +166,97 @@ fn main() -> ExitCode {
 
     let args = Args::parse();
 
-    let joplin = JoplinData::new(args.token.clone()).unwrap();
+    // Cron wrappers tell what went wrong by the exit code, see BridgeError::exit_code
+    match run(args) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            error!("{}", e);
+            ExitCode::from(e.exit_code())
+        }
+    }
+}
+
+fn run(args: Args) -> Result<(), BridgeError> {
+    let token = auth::get_token(args.joplin_url.trim_end_matches('/'), args.token.clone(), args.dry_run)?;
+    let mut joplin = JoplinData::new(token, &args.joplin_url)?;
+
+    let mut feather: FeatherStruct = FeatherStruct::new();
+    let mut feather_path: Option<String> = args.feather_file.clone();
+    if feather_path.is_none() && args.sync && std::path::Path::new(&args.output_file).exists() {
+        // Sync continues where the last one ended
+        feather_path = Some(args.output_file.clone());
+    }
+    if let Some(feather_file) = &feather_path {
+        feather = FeatherStruct::read(feather_file.clone(), args.lazy)?;
+    }
+
+    // Attachment links are relative to the .fnx that is written, or read for --overwrite-joplin
+    let fnx_path = match (&feather_path, args.overwrite_joplin) {
+        (Some(x), true) => x.clone(),
+        _ => args.output_file.clone(),
+    };
+    joplin.attachments =
+        AttachmentOptions::new(&fnx_path, args.attachments, args.attachment_embed_limit);
+    joplin.math = args.math;
+    joplin.converter = args.converter;
+    joplin.tag_mode = args.tags;
+    joplin.jobs = args.jobs.into();
+    if args.tags != TagMode::None || args.tags_tree.is_some() {
+        joplin.load_tags()?;
+    }
 
     let folders_to_get: Vec<FoldersArray>;
-    if let Some(folder_id) = args.joplin_folder_id {
-        folders_to_get = joplin.lookup_folder(folder_id).unwrap();
+    if let Some(folder_id) = &args.joplin_folder_id {
+        folders_to_get = joplin.lookup_folder(folder_id.clone())?;
     } else {
         folders_to_get = joplin.dir_list.clone(); // For root, everything
     }
 
-    let mut feather: FeatherStruct = FeatherStruct::new();
-    if let Some(feather_file) = args.feather_file {
-        feather = FeatherStruct::read(feather_file).unwrap();
+    // Nearly every body gets converted, so they come with the listing. The listing has the
+    // whole profile, a single folder asks for its own notes instead
+    if args.overwrite_feather && !args.dry_run && args.joplin_folder_id.is_none() {
+        joplin.load_note_bodies()?;
     }
 
-    if args.overwrite_feather {
-        overwrite_joplin_to_feathernotes(feather, joplin, folders_to_get, &args.output_file);
+    let plan: Plan = if args.overwrite_feather {
+        overwrite_joplin_to_feathernotes(
+            feather,
+            joplin,
+            folders_to_get,
+            &args.output_file,
+            args.tags_tree,
+            args.backups,
+            args.dry_run,
+        )?
+    } else if args.sync {
+        let options = SyncOptions {
+            conflict: args.conflict,
+            feather_modified_time: feather_path
+                .map(|x| modified_time_millis(&x))
+                .unwrap_or_default(),
+            trash_node: args.trash_node,
+            tags_tree: args.tags_tree,
+            backups: args.backups,
+        };
+        sync_joplin_and_feathernotes(
+            feather,
+            joplin,
+            folders_to_get,
+            args.joplin_folder_id,
+            &args.output_file,
+            options,
+            args.dry_run,
+        )?
+    } else if args.overwrite_joplin {
+        overwrite_feathernotes_to_joplin(feather, joplin, args.joplin_folder_id, args.dry_run)?
     } else {
-        println!("Sorry, only overwrite_feather option is available now");
+        println!("No mode selected, use --overwrite-feather, --overwrite-joplin or --sync");
+        return Ok(());
+    };
+
+    if args.dry_run {
+        print!("{}", plan.render(args.plan_format));
     }
 
-    ExitCode::SUCCESS
+    Ok(())
 }
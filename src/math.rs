@@ -0,0 +1,162 @@
+use crate::attachments::data_uri;
+use crate::error::BridgeError;
+use crate::util::{cache_dir, create_private_dir, hash_body};
+use regex::Regex;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::Mutex;
+
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MathMode {
+    Tex,    // Left as \( \) TeX in the html, nothing lost
+    Mathml, // Rendered to MathML by pandoc
+    Image,  // Rendered to svg with latex and dvisvgm, embedded in the note
+    Webtex, // Images from an online service, needs internet while converting
+}
+
+fn math_cache_dir() -> PathBuf {
+    cache_dir("math")
+}
+
+fn run_tool(command: &mut Command) -> Result<(), BridgeError> {
+    let output = command.output()?;
+    if !output.status.success() {
+        return Err(BridgeError::Conversion(format!(
+            "{:?} failed: {}",
+            command.get_program(),
+            String::from_utf8_lossy(&output.stdout)
+                .lines()
+                .find(|x| x.starts_with('!'))
+                .unwrap_or("no error message")
+        )));
+    }
+    Ok(())
+}
+
+// latex leaves its files in the cache dir too, so workers render one formula at a time
+static RENDERING: Mutex<()> = Mutex::new(());
+
+// One standalone document per formula, the svg is cached by the hash of the formula
+fn render_svg(tex: &str, display: bool, dir: &Path) -> Result<String, BridgeError> {
+    let _rendering = RENDERING.lock().unwrap_or_else(|x| x.into_inner());
+    let name = hash_body(&format!("{}{}", display, tex));
+    let svg_file = dir.join(format!("{}.svg", name));
+    if !svg_file.exists() {
+        create_private_dir(dir)?;
+        let math = if display {
+            format!("$\\displaystyle {}$", tex)
+        } else {
+            format!("${}$", tex)
+        };
+        let document = format!(
+            "\\documentclass[preview]{{standalone}}\n\\usepackage{{amsmath,amssymb}}\n\\begin{{document}}\n{}\n\\end{{document}}\n",
+            math
+        );
+        std::fs::write(dir.join(format!("{}.tex", name)), document)?;
+
+        run_tool(
+            Command::new("latex")
+                .current_dir(dir)
+                .args(["-interaction=nonstopmode", "-halt-on-error"])
+                .arg(format!("{}.tex", name)),
+        )?;
+        run_tool(
+            Command::new("dvisvgm")
+                .current_dir(dir)
+                .args(["--no-fonts", "--exact", "-o"])
+                .arg(format!("{}.svg", name))
+                .arg(format!("{}.dvi", name)),
+        )?;
+    }
+    Ok(std::fs::read_to_string(svg_file)?)
+}
+
+// $x$ and $$x$$ outside of code, replace gets the whole formula, the TeX and if it is display
+// math. Code comes first in the alternation so a $ in it is never taken for math
+fn replace_math(md: &str, mut replace: impl FnMut(&str, &str, bool) -> String) -> String {
+    let re_math = Regex::new(
+        r"(?ms)(^[ \t]*```.*?^[ \t]*```[ \t]*$|^[ \t]*~~~.*?^[ \t]*~~~[ \t]*$|``.+?``|`[^`]+`)|\$\$(.+?)\$\$|\$(.+?)\$",
+    )
+    .unwrap();
+
+    re_math
+        .replace_all(md, |cap: &regex::Captures| {
+            let (tex, display) = match (cap.get(2), cap.get(3)) {
+                (Some(x), _) => (x.as_str(), true),
+                (_, Some(x)) => (x.as_str(), false),
+                _ => return cap[0].to_string(),
+            };
+            // Pandoc's rule, so prices like $5 and $6 aren't formulas
+            if tex.starts_with(char::is_whitespace) || tex.ends_with(char::is_whitespace) {
+                return cap[0].to_string();
+            }
+            replace(&cap[0], tex, display)
+        })
+        .to_string()
+}
+
+// After repair_md_katex: $x$ and $$x$$ become ![\$x\$](data:image/svg+xml...), the alt text keeps
+// the TeX so restore_math_images_md can bring it back. Formulas that fail stay TeX
+pub fn render_math_images_md(md: String) -> String {
+    // Escaped so pandoc doesn't read the alt text as math too
+    let re_punct = Regex::new(r"([[:punct:]])").unwrap();
+    let dir = math_cache_dir();
+    let mut rendered: HashMap<String, Option<String>> = HashMap::new();
+
+    replace_math(&md, |formula, tex, display| {
+        let image = rendered.entry(formula.to_string()).or_insert_with(|| {
+            match render_svg(tex, display, &dir) {
+                Ok(svg) => Some(data_uri("image/svg+xml", svg.as_bytes())),
+                Err(x) => {
+                    warn!(
+                        "Couldn't render {} to an image, leaving TeX: {}",
+                        formula, x
+                    );
+                    None
+                }
+            }
+        });
+        match image {
+            Some(uri) => format!("![{}]({})", re_punct.replace_all(formula, r"\$1"), uri),
+            None => formula.to_string(),
+        }
+    })
+}
+
+// The other way, pandoc escapes punctuation in the alt text so that is undone too
+pub fn restore_math_images_md(md: String) -> String {
+    let re_images =
+        Regex::new(r"!\[(\\?\$(?:[^\]\\]|\\.)*?\\?\$)\]\(data:image/svg\+xml;base64,[^)]*\)")
+            .unwrap();
+    let re_escapes = Regex::new(r"\\([[:punct:]])").unwrap();
+
+    re_images
+        .replace_all(&md, |cap: &regex::Captures| {
+            re_escapes.replace_all(&cap[1], "$1").to_string()
+        })
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The corpus note has math in every kind of code, only the formulas outside of it change
+    #[test]
+    fn math_in_code_stays_code() {
+        let md = std::fs::read_to_string("tests/fixtures/corpus/math_in_code.md").unwrap();
+        let mut found = Vec::new();
+        let replaced = replace_math(&md, |_, tex, display| {
+            found.push((tex.to_string(), display));
+            "MATH".to_string()
+        });
+
+        assert_eq!(
+            found,
+            [("a + b".to_string(), false), ("c".to_string(), true)]
+        );
+        let expected = md.replacen("$a + b$", "MATH", 1).replace("$$c$$", "MATH");
+        assert_eq!(replaced, expected);
+    }
+}
@@ -0,0 +1,1087 @@
+use crate::attachments::{data_uri, mime_for_extension};
+use crate::converter::Converter;
+use crate::data_types::joplin::resource_dirs;
+use crate::error::BridgeError;
+use crate::math::MathMode;
+use crate::pandoc::write_debug_file;
+use pulldown_cmark::{Alignment, CodeBlockKind, Event, HeadingLevel, Options, Parser, Tag, TagEnd};
+use regex::Regex;
+use std::collections::HashMap;
+use std::path::Path;
+
+// What FeatherNotes itself writes before the body, Qt 6 QTextDocument::toHtml
+const QT_HEADER: &str = r#"<!DOCTYPE HTML PUBLIC "-//W3C//DTD HTML 4.0//EN" "http://www.w3.org/TR/REC-html40/strict.dtd">
+<html><head><meta name="qrichtext" content="1" /><meta charset="utf-8" /><style type="text/css">
+p, li { white-space: pre-wrap; }
+hr { height: 1px; border-width: 0; }
+li.unchecked::marker { content: "\2610"; }
+li.checked::marker { content: "\2612"; }
+</style></head><body style=" font-family:'Monospace'; font-size:11pt; font-weight:400; font-style:normal;">"#;
+const QT_FOOTER: &str = "</body></html>";
+
+const STYLE_BOLD: &str = " font-weight:600;";
+const STYLE_ITALIC: &str = " font-style:italic;";
+const STYLE_STRIKE: &str = " text-decoration: line-through;";
+const STYLE_LINK: &str = " text-decoration: underline; color:#0000ff;";
+const STYLE_CODE: &str = " font-family:'monospace';";
+
+pub struct NativeConverter {
+    pub math: MathMode,
+}
+
+impl Converter for NativeConverter {
+    fn md_to_html(&self, md: String) -> Result<String, BridgeError> {
+        let mut writer = QtWriter::new(self.math);
+        writer.write(&md);
+        let html = format!("{}{}{}", QT_HEADER, writer.out, QT_FOOTER);
+        write_debug_file("", html.clone(), ".html");
+        Ok(html)
+    }
+
+    fn html_to_md(&self, html: String) -> Result<String, BridgeError> {
+        let md = QtReader::new().read(&html);
+        write_debug_file("", md.clone(), ".md");
+        Ok(md)
+    }
+}
+
+fn block_style(margin_top: u32, margin_bottom: u32, indent: usize) -> String {
+    format!(
+        " margin-top:{}px; margin-bottom:{}px; margin-left:0px; margin-right:0px; -qt-block-indent:{}; text-indent:0px;",
+        margin_top, margin_bottom, indent
+    )
+}
+
+fn heading_style(level: HeadingLevel) -> (u32, u32, &'static str) {
+    match level {
+        HeadingLevel::H1 => (18, 12, " font-size:xx-large; font-weight:600;"),
+        HeadingLevel::H2 => (16, 12, " font-size:x-large; font-weight:600;"),
+        HeadingLevel::H3 => (14, 12, " font-size:large; font-weight:600;"),
+        HeadingLevel::H4 => (12, 12, " font-size:medium; font-weight:600;"),
+        HeadingLevel::H5 => (12, 4, " font-size:small; font-weight:600;"),
+        HeadingLevel::H6 => (12, 4, " font-size:x-small; font-weight:600;"),
+    }
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn percent_encode(text: &str) -> String {
+    text.bytes()
+        .map(|x| match x {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                (x as char).to_string()
+            }
+            _ => format!("%{:02X}", x),
+        })
+        .collect()
+}
+
+// Qt shows it as the tooltip and writes it back
+fn title_attribute(title: &str) -> String {
+    if title.is_empty() {
+        String::new()
+    } else {
+        format!(" title=\"{}\"", escape_html(title))
+    }
+}
+
+// Pandoc embeds images with --self-contained, here it's done by hand from the resource dirs
+fn resolve_image(src: &str) -> String {
+    if src.starts_with("data:") || src.contains("://") {
+        return src.to_string();
+    }
+    for dir in resource_dirs() {
+        let file = dir.join(src);
+        if file.is_file() {
+            let extension = Path::new(src)
+                .extension()
+                .map(|x| x.to_string_lossy().to_string())
+                .unwrap_or_default();
+            match std::fs::read(&file) {
+                Ok(bytes) => return data_uri(mime_for_extension(&extension), &bytes),
+                Err(x) => warn!("Couldn't read {}: {}", file.display(), x),
+            }
+        }
+    }
+    warn!("Image {} isn't in the resource dirs, leaving the link", src);
+    src.to_string()
+}
+
+// Markdown events to Qt rich text, one block element per paragraph like QTextDocument does it
+struct QtWriter {
+    math: MathMode,
+    out: String,
+    styles: Vec<&'static str>, // Active inline styles, merged into one span
+    lists: Vec<Option<u64>>,   // None is a bullet list
+    item_open: Vec<bool>,      // An <li> waits for its </li>
+    item_has_content: bool,    // A second paragraph in an item goes after a <br />
+    quote_depth: usize,
+    in_table: bool,
+    table_head: bool,
+    table_alignments: Vec<Alignment>,
+    table_column: usize,
+    blocks: usize, // Top level blocks written, they get an empty paragraph between them
+    last_quote_depth: usize,
+    image: Option<(String, String, String)>, // Source, title and alt text while inside ![..](..)
+    code_block: Option<String>,
+    warned_mathml: bool,
+}
+
+impl QtWriter {
+    fn new(math: MathMode) -> QtWriter {
+        QtWriter {
+            math,
+            out: String::new(),
+            styles: Vec::new(),
+            lists: Vec::new(),
+            item_open: Vec::new(),
+            item_has_content: false,
+            quote_depth: 0,
+            in_table: false,
+            table_head: false,
+            table_alignments: Vec::new(),
+            table_column: 0,
+            blocks: 0,
+            last_quote_depth: 0,
+            image: None,
+            code_block: None,
+            warned_mathml: false,
+        }
+    }
+
+    fn write(&mut self, md: &str) {
+        let options = Options::ENABLE_TABLES
+            | Options::ENABLE_STRIKETHROUGH
+            | Options::ENABLE_MATH
+            | Options::ENABLE_TASKLISTS;
+        for event in Parser::new_ext(md, options) {
+            self.event(event);
+        }
+    }
+
+    fn in_item(&self) -> bool {
+        self.item_open.last() == Some(&true)
+    }
+
+    // Blank lines in markdown are empty paragraphs in FeatherNotes
+    fn start_block(&mut self) {
+        if !self.lists.is_empty() || self.in_table {
+            return;
+        }
+        if self.blocks > 0 {
+            self.out.push_str(&format!(
+                "\n<p style=\"-qt-paragraph-type:empty;{}\"><br /></p>",
+                block_style(0, 0, self.quote_depth.min(self.last_quote_depth))
+            ));
+        }
+        self.blocks += 1;
+        self.last_quote_depth = self.quote_depth;
+    }
+
+    fn text(&mut self, text: &str) {
+        if let Some((_, _, alt)) = &mut self.image {
+            alt.push_str(text);
+            return;
+        }
+        if let Some(code) = &mut self.code_block {
+            code.push_str(text);
+            return;
+        }
+        if self.styles.is_empty() {
+            self.out.push_str(&escape_html(text));
+        } else {
+            self.out.push_str(&format!(
+                "<span style=\"{}\">{}</span>",
+                self.styles.concat(),
+                escape_html(text)
+            ));
+        }
+    }
+
+    fn math(&mut self, tex: &str, display: bool) {
+        match self.math {
+            MathMode::Webtex => {
+                let tex = if display {
+                    format!("\\displaystyle {}", tex)
+                } else {
+                    tex.to_string()
+                };
+                self.out.push_str(&format!(
+                    "<img src=\"https://latex.codecogs.com/png.latex?{}\" alt=\"{}\" />",
+                    percent_encode(&tex),
+                    escape_html(&tex)
+                ));
+            }
+            _ => {
+                if self.math == MathMode::Mathml && !self.warned_mathml {
+                    warn!("The native converter can't do MathML, formulas stay TeX");
+                    self.warned_mathml = true;
+                }
+                if display {
+                    self.text(&format!("\\[{}\\]", tex));
+                } else {
+                    self.text(&format!("\\({}\\)", tex));
+                }
+            }
+        }
+    }
+
+    fn event(&mut self, event: Event) {
+        match event {
+            Event::Start(tag) => self.start(tag),
+            Event::End(tag) => self.end(tag),
+            Event::Text(text) => self.text(&text),
+            Event::Code(code) => {
+                self.styles.push(STYLE_CODE);
+                self.text(&code);
+                self.styles.pop();
+            }
+            Event::InlineMath(tex) => self.math(&tex, false),
+            Event::DisplayMath(tex) => self.math(&tex, true),
+            Event::Html(html) | Event::InlineHtml(html) => self.out.push_str(&html),
+            // Qt has one kind of line break, a hard one comes back as a soft one
+            Event::SoftBreak | Event::HardBreak => self.out.push_str("<br />"),
+            Event::Rule => {
+                self.start_block();
+                self.out.push_str("\n<hr />");
+            }
+            Event::FootnoteReference(name) => self.text(&format!("[^{}]", name)),
+            Event::TaskListMarker(checked) => {
+                // FeatherNotes draws the box from the class of the item, it comes after <li is written
+                if let Some(position) = self.out.rfind("<li ") {
+                    let class = if checked { "checked" } else { "unchecked" };
+                    self.out
+                        .insert_str(position + 3, &format!(" class=\"{}\"", class));
+                }
+            }
+        }
+    }
+
+    fn start(&mut self, tag: Tag) {
+        match tag {
+            Tag::Paragraph => {
+                if self.in_item() {
+                    if self.item_has_content {
+                        self.out.push_str("<br />");
+                    }
+                } else if self.in_table {
+                    self.out
+                        .push_str(&format!("<p style=\"{}\">", block_style(0, 0, 0)));
+                } else {
+                    // After a nested list the rest of the item is an indented paragraph
+                    let indent = match self.item_open.is_empty() {
+                        true => self.quote_depth,
+                        false => self.quote_depth + self.lists.len(),
+                    };
+                    self.start_block();
+                    self.out
+                        .push_str(&format!("\n<p style=\"{}\">", block_style(0, 0, indent)));
+                }
+            }
+            Tag::Heading { level, .. } => {
+                let (top, bottom, style) = heading_style(level);
+                self.start_block();
+                self.out.push_str(&format!(
+                    "\n<{} style=\"{}\">",
+                    level,
+                    block_style(top, bottom, self.quote_depth)
+                ));
+                self.styles.push(style);
+            }
+            Tag::BlockQuote(_) => self.quote_depth += 1,
+            Tag::CodeBlock(kind) => {
+                // Only the first word of the info string is the language
+                let class = match &kind {
+                    CodeBlockKind::Fenced(info) => match info.split_whitespace().next() {
+                        Some(language) => {
+                            format!(" class=\"language-{}\"", escape_html(language))
+                        }
+                        None => String::new(),
+                    },
+                    CodeBlockKind::Indented => String::new(),
+                };
+                self.start_block();
+                self.out.push_str(&format!(
+                    "\n<pre{} style=\"{}\">",
+                    class,
+                    block_style(0, 0, self.quote_depth)
+                ));
+                self.code_block = Some(String::new());
+            }
+            Tag::List(start) => {
+                // Qt puts a nested list after the item, not inside it
+                if self.in_item() {
+                    self.out.push_str("</li>");
+                    *self.item_open.last_mut().unwrap() = false;
+                }
+                self.start_block();
+                self.lists.push(start);
+                let list_type = match start {
+                    Some(_) => "ol",
+                    None => "ul",
+                };
+                self.out.push_str(&format!(
+                    "\n<{} style=\"margin-top: 0px; margin-bottom: 0px; margin-left: 0px; margin-right: 0px; -qt-list-indent: {};\"",
+                    list_type,
+                    self.lists.len()
+                ));
+                match start {
+                    Some(x) if x != 1 => self.out.push_str(&format!(" start=\"{}\">", x)),
+                    _ => self.out.push('>'),
+                }
+            }
+            Tag::Item => {
+                self.out.push_str(&format!(
+                    "\n<li style=\"{}\">",
+                    block_style(0, 0, self.quote_depth)
+                ));
+                self.item_open.push(true);
+                self.item_has_content = false;
+            }
+            Tag::Table(alignments) => {
+                self.start_block();
+                self.in_table = true;
+                self.table_alignments = alignments;
+                self.out.push_str("\n<table border=\"1\" style=\" margin-top:0px; margin-bottom:0px; margin-left:0px; margin-right:0px;\" cellspacing=\"2\" cellpadding=\"0\">");
+            }
+            Tag::TableHead => {
+                self.table_head = true;
+                self.table_column = 0;
+                self.out.push_str("\n<tr>");
+            }
+            Tag::TableRow => {
+                self.table_column = 0;
+                self.out.push_str("\n<tr>");
+            }
+            Tag::TableCell => {
+                let align = match self.table_alignments.get(self.table_column) {
+                    Some(Alignment::Left) => " align=\"left\"",
+                    Some(Alignment::Center) => " align=\"center\"",
+                    Some(Alignment::Right) => " align=\"right\"",
+                    _ => "",
+                };
+                self.table_column += 1;
+                self.out.push_str(&format!("\n<td{}>", align));
+                if self.table_head {
+                    self.styles.push(STYLE_BOLD);
+                }
+            }
+            Tag::Emphasis => self.styles.push(STYLE_ITALIC),
+            Tag::Strong => self.styles.push(STYLE_BOLD),
+            Tag::Strikethrough => self.styles.push(STYLE_STRIKE),
+            Tag::Link {
+                dest_url, title, ..
+            } => {
+                self.out.push_str(&format!(
+                    "<a href=\"{}\"{}>",
+                    escape_html(&dest_url),
+                    title_attribute(&title)
+                ));
+                self.styles.push(STYLE_LINK);
+            }
+            Tag::Image {
+                dest_url, title, ..
+            } => {
+                self.image = Some((dest_url.to_string(), title.to_string(), String::new()));
+            }
+            Tag::HtmlBlock => self.start_block(),
+            Tag::FootnoteDefinition(_)
+            | Tag::DefinitionList
+            | Tag::DefinitionListTitle
+            | Tag::DefinitionListDefinition
+            | Tag::MetadataBlock(_) => {}
+        }
+    }
+
+    fn end(&mut self, tag: TagEnd) {
+        match tag {
+            TagEnd::Paragraph => {
+                if self.in_item() {
+                    self.item_has_content = true;
+                } else {
+                    self.out.push_str("</p>");
+                }
+            }
+            TagEnd::Heading(level) => {
+                self.styles.pop();
+                self.out.push_str(&format!("</{}>", level));
+            }
+            TagEnd::BlockQuote(_) => self.quote_depth -= 1,
+            TagEnd::CodeBlock => {
+                if let Some(code) = self.code_block.take() {
+                    // The last line ending is the end of the block
+                    self.styles.push(STYLE_CODE);
+                    self.text(code.strip_suffix('\n').unwrap_or(&code));
+                    self.styles.pop();
+                }
+                self.out.push_str("</pre>");
+            }
+            TagEnd::List(ordered) => {
+                self.lists.pop();
+                self.out.push_str(if ordered { "</ol>" } else { "</ul>" });
+            }
+            TagEnd::Item => {
+                if self.item_open.pop() == Some(true) {
+                    self.out.push_str("</li>");
+                }
+            }
+            TagEnd::Table => {
+                self.in_table = false;
+                self.out.push_str("</table>");
+            }
+            TagEnd::TableHead => {
+                self.table_head = false;
+                self.out.push_str("</tr>");
+            }
+            TagEnd::TableRow => self.out.push_str("</tr>"),
+            TagEnd::TableCell => {
+                if self.table_head {
+                    self.styles.pop();
+                }
+                self.out.push_str("</td>");
+            }
+            TagEnd::Emphasis | TagEnd::Strong | TagEnd::Strikethrough => {
+                self.styles.pop();
+            }
+            TagEnd::Link => {
+                self.styles.pop();
+                self.out.push_str("</a>");
+            }
+            TagEnd::Image => {
+                if let Some((src, title, alt)) = self.image.take() {
+                    self.out.push_str(&format!(
+                        "<img src=\"{}\" alt=\"{}\"{} />",
+                        escape_html(&resolve_image(&src)),
+                        escape_html(&alt),
+                        title_attribute(&title)
+                    ));
+                }
+            }
+            TagEnd::HtmlBlock
+            | TagEnd::FootnoteDefinition
+            | TagEnd::DefinitionList
+            | TagEnd::DefinitionListTitle
+            | TagEnd::DefinitionListDefinition
+            | TagEnd::MetadataBlock(_) => {}
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+struct Format {
+    bold: bool,
+    italic: bool,
+    strike: bool,
+    code: bool,
+}
+
+impl Format {
+    // Qt writes the whole format of a run in one span style
+    fn from_style(style: &str, parent: Format) -> Format {
+        let mut format = parent;
+        for property in style.split(';') {
+            let (name, value) = match property.split_once(':') {
+                Some((name, value)) => (name.trim(), value.trim()),
+                None => continue,
+            };
+            match name {
+                "font-weight" => {
+                    format.bold = value == "bold" || value.parse::<u32>().unwrap_or(400) >= 600
+                }
+                "font-style" => format.italic = value == "italic" || value == "oblique",
+                "text-decoration" => format.strike = value.contains("line-through"),
+                "font-family" => {
+                    let value = value.to_lowercase();
+                    format.code = value.contains("mono") || value.contains("courier");
+                }
+                _ => {}
+            }
+        }
+        format
+    }
+
+    // Opened in this order, closed the other way around. Code is innermost, nothing goes inside it
+    fn markers(&self) -> Vec<&'static str> {
+        let mut markers = Vec::new();
+        if self.bold {
+            markers.push("**");
+        }
+        if self.italic {
+            markers.push("*");
+        }
+        if self.strike {
+            markers.push("~~");
+        }
+        if self.code {
+            markers.push("`");
+        }
+        markers
+    }
+}
+
+enum Inline {
+    Run(String, Format),
+    Raw(String), // Already markdown, links and images
+    Break,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum BlockKind {
+    Paragraph(usize), // -qt-block-indent, written as > quotes
+    Empty(usize),
+    Heading(usize),
+    Item(String, String), // Marker with its indent, and the indent of following lines
+    Pre,
+    Cell,
+}
+
+enum Token {
+    Open(String, HashMap<String, String>),
+    Close(String),
+    Text(String),
+}
+
+fn decode_entities(text: &str) -> String {
+    let re_entity = Regex::new(r"&(#x[0-9a-fA-F]+|#[0-9]+|[a-zA-Z]+);").unwrap();
+    re_entity
+        .replace_all(text, |cap: &regex::Captures| {
+            let entity = &cap[1];
+            let decoded = if let Some(hex) = entity.strip_prefix("#x") {
+                u32::from_str_radix(hex, 16).ok().and_then(char::from_u32)
+            } else if let Some(decimal) = entity.strip_prefix('#') {
+                decimal.parse::<u32>().ok().and_then(char::from_u32)
+            } else {
+                match entity {
+                    "lt" => Some('<'),
+                    "gt" => Some('>'),
+                    "amp" => Some('&'),
+                    "quot" => Some('"'),
+                    "apos" => Some('\''),
+                    "nbsp" => Some('\u{a0}'),
+                    _ => None,
+                }
+            };
+            match decoded {
+                Some(x) => x.to_string(),
+                None => cap[0].to_string(),
+            }
+        })
+        .to_string()
+}
+
+// Qt's html is regular enough that a full html parser isn't needed
+fn tokenize(html: &str) -> Vec<Token> {
+    let re_tag = Regex::new(
+        r#"(?s)<!--.*?-->|<![^>]*>|<(/?)([a-zA-Z][a-zA-Z0-9]*)((?:[^>"']|"[^"]*"|'[^']*')*)>"#,
+    )
+    .unwrap();
+    let re_attr = Regex::new(
+        r#"([a-zA-Z_:][-a-zA-Z0-9_:.]*)\s*(?:=\s*(?:"([^"]*)"|'([^']*)'|([^\s"'>]+)))?"#,
+    )
+    .unwrap();
+
+    let mut tokens = Vec::new();
+    let mut last = 0;
+    for cap in re_tag.captures_iter(html) {
+        let whole = cap.get(0).unwrap();
+        if whole.start() > last {
+            tokens.push(Token::Text(decode_entities(&html[last..whole.start()])));
+        }
+        last = whole.end();
+
+        let name = match cap.get(2) {
+            Some(x) => x.as_str().to_lowercase(),
+            None => continue, // Comment or doctype
+        };
+        if &cap[1] == "/" {
+            tokens.push(Token::Close(name));
+            continue;
+        }
+        let mut attrs = HashMap::new();
+        for attr in re_attr.captures_iter(&cap[3]) {
+            let value = attr
+                .get(2)
+                .or(attr.get(3))
+                .or(attr.get(4))
+                .map(|x| decode_entities(x.as_str()))
+                .unwrap_or_default();
+            attrs.insert(attr[1].to_lowercase(), value);
+        }
+        tokens.push(Token::Open(name.clone(), attrs));
+        if cap[3].trim_end().ends_with('/') {
+            tokens.push(Token::Close(name));
+        }
+    }
+    if last < html.len() {
+        tokens.push(Token::Text(decode_entities(&html[last..])));
+    }
+    tokens
+}
+
+// The part of a link or image after the target, with a space
+fn md_title(attrs: &HashMap<String, String>) -> String {
+    match attrs.get("title") {
+        Some(title) if !title.is_empty() => format!(" \"{}\"", title.replace('"', "\\\"")),
+        _ => String::new(),
+    }
+}
+
+fn escape_md(text: &str) -> String {
+    let mut escaped = String::new();
+    for c in text.chars() {
+        if matches!(c, '\\' | '`' | '*' | '_' | '[' | ']' | '<' | '$' | '~') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+// Things that would start a markdown block at the start of a line
+fn escape_line_start(line: &str) -> String {
+    let re_block = Regex::new(r"^(\s*)(\d*)([#>+.)-])").unwrap();
+    match re_block.captures(line) {
+        // 1. is a list, 1 # isn't anything
+        Some(cap) if cap[2].is_empty() != matches!(&cap[3], "." | ")") => {
+            re_block.replace(line, r"$1$2\$3").to_string()
+        }
+        _ => line.to_string(),
+    }
+}
+
+// Text runs with \( \) and \[ \] TeX become $ and $$ math, the rest is escaped
+fn text_to_md(text: &str, code: bool) -> String {
+    if code {
+        return text.to_string();
+    }
+    let re_math = Regex::new(r"(?s)\\\((.+?)\\\)|\\\[(.+?)\\\]").unwrap();
+    let mut md = String::new();
+    let mut last = 0;
+    for cap in re_math.captures_iter(text) {
+        let whole = cap.get(0).unwrap();
+        md.push_str(&escape_md(&text[last..whole.start()]));
+        match (cap.get(1), cap.get(2)) {
+            (Some(x), _) => md.push_str(&format!("${}$", x.as_str())),
+            (_, Some(x)) => md.push_str(&format!("$${}$$", x.as_str())),
+            _ => {}
+        }
+        last = whole.end();
+    }
+    md.push_str(&escape_md(&text[last..]));
+    md
+}
+
+// Emphasis can't start or end with a space, so markers are put around the words only.
+// In headings and table headers bold is what the block looks like anyway
+fn inlines_to_md(inlines: &[Inline], bold_block: bool, line_break: &str) -> String {
+    let mut md = String::new();
+    let mut open: Vec<&'static str> = Vec::new();
+    let mut pending_space = String::new();
+
+    fn close_all(md: &mut String, open: &mut Vec<&'static str>) {
+        while let Some(marker) = open.pop() {
+            md.push_str(marker);
+        }
+    }
+
+    for inline in inlines {
+        match inline {
+            Inline::Run(text, format) => {
+                let mut format = *format;
+                if bold_block {
+                    format.bold = false;
+                }
+                let core = text.trim();
+                if core.is_empty() {
+                    pending_space.push_str(text);
+                    continue;
+                }
+                let markers = format.markers();
+                // Close everything from the first marker that isn't wanted anymore
+                if let Some(position) = open.iter().position(|x| !markers.contains(x)) {
+                    while open.len() > position {
+                        md.push_str(open.pop().unwrap());
+                    }
+                }
+                let lead = &text[..text.len() - text.trim_start().len()];
+                let trail = &text[text.trim_end().len()..];
+                md.push_str(&pending_space);
+                md.push_str(lead);
+                pending_space.clear();
+                // Code has to be the innermost, reopen it if something comes before it
+                if open.last() == Some(&"`") && markers.len() > open.len() {
+                    md.push_str(open.pop().unwrap());
+                }
+                for marker in markers {
+                    if !open.contains(&marker) {
+                        md.push_str(marker);
+                        open.push(marker);
+                    }
+                }
+                md.push_str(&text_to_md(core, format.code));
+                pending_space.push_str(trail);
+            }
+            Inline::Raw(raw) => {
+                close_all(&mut md, &mut open);
+                md.push_str(&pending_space);
+                pending_space.clear();
+                md.push_str(raw);
+            }
+            Inline::Break => {
+                close_all(&mut md, &mut open);
+                pending_space.clear();
+                md.push_str(line_break);
+            }
+        }
+    }
+    close_all(&mut md, &mut open);
+    md
+}
+
+// FeatherNotes html back to Joplin markdown, one line per Qt block
+struct QtReader {
+    lines: Vec<String>,
+    block: Option<BlockKind>,
+    inlines: Vec<Inline>,
+    formats: Vec<Format>,
+    lists: Vec<(bool, u64)>, // Ordered and the next number
+    list_indents: Vec<String>,
+    link: Option<(String, String)>, // Target and title
+    code_lines: Vec<String>,        // Qt writes code line by line in <pre>, joined into one fence
+    code_language: String,
+    table: Option<Vec<Vec<String>>>,
+    table_alignments: Vec<String>, // From the cells of the first row
+    in_body: bool,
+}
+
+impl QtReader {
+    fn new() -> QtReader {
+        QtReader {
+            lines: Vec::new(),
+            block: None,
+            inlines: Vec::new(),
+            formats: Vec::new(),
+            lists: Vec::new(),
+            list_indents: Vec::new(),
+            link: None,
+            code_lines: Vec::new(),
+            code_language: String::new(),
+            table: None,
+            table_alignments: Vec::new(),
+            in_body: false,
+        }
+    }
+
+    fn format(&self) -> Format {
+        self.formats.last().copied().unwrap_or_default()
+    }
+
+    fn read(mut self, html: &str) -> String {
+        for token in tokenize(html) {
+            match token {
+                Token::Open(name, attrs) => self.open(&name, &attrs),
+                Token::Close(name) => self.close(&name),
+                Token::Text(text) => {
+                    if self.block.is_some() {
+                        let format = self.format();
+                        self.inlines.push(Inline::Run(text, format));
+                    }
+                }
+            }
+        }
+        self.finish_block();
+        self.flush_code();
+
+        // Several empty paragraphs are still one blank line in markdown
+        self.lines.dedup_by(|a, b| a.is_empty() && b.is_empty());
+        let md = self.lines.join("\n");
+        format!("{}\n", md.trim_matches('\n'))
+    }
+
+    fn start_block(&mut self, kind: BlockKind) {
+        self.finish_block();
+        self.block = Some(kind);
+        self.formats.clear();
+    }
+
+    fn flush_code(&mut self) {
+        if self.code_lines.is_empty() {
+            return;
+        }
+        self.lines
+            .push(format!("```{}", std::mem::take(&mut self.code_language)));
+        self.lines.append(&mut self.code_lines);
+        self.lines.push("```".to_string());
+    }
+
+    fn finish_block(&mut self) {
+        let kind = match self.block.take() {
+            Some(x) => x,
+            None => return,
+        };
+        let inlines = std::mem::take(&mut self.inlines);
+
+        if kind == BlockKind::Pre {
+            let mut code = String::new();
+            for inline in inlines {
+                match inline {
+                    Inline::Run(text, _) | Inline::Raw(text) => code.push_str(&text),
+                    Inline::Break => code.push('\n'),
+                }
+            }
+            self.code_lines.extend(code.lines().map(|x| x.to_string()));
+            return;
+        }
+        self.flush_code();
+
+        match kind {
+            BlockKind::Paragraph(indent) => {
+                let quote = "> ".repeat(indent);
+                let text = inlines_to_md(&inlines, false, &format!("\n{}", quote));
+                for line in text.split('\n') {
+                    let line = line.strip_prefix(&quote).unwrap_or(line);
+                    self.lines.push(format!(
+                        "{}{}",
+                        quote,
+                        escape_line_start(line.trim()).trim_end()
+                    ));
+                }
+            }
+            BlockKind::Empty(indent) => self.lines.push(">".repeat(indent)),
+            BlockKind::Heading(level) => {
+                let text = inlines_to_md(&inlines, true, " ");
+                self.lines
+                    .push(format!("{} {}", "#".repeat(level), text.trim()));
+            }
+            BlockKind::Item(marker, continuation) => {
+                let text = inlines_to_md(&inlines, false, &format!("\n{}", continuation));
+                self.lines
+                    .push(format!("{}{}", marker, text.trim()).trim_end().to_string());
+            }
+            BlockKind::Cell => {
+                // The header row is bold anyway
+                let header = self.table.as_ref().map(|x| x.len() == 1).unwrap_or(false);
+                let text = inlines_to_md(&inlines, header, "<br>");
+                if let Some(row) = self.table.as_mut().and_then(|x| x.last_mut()) {
+                    row.push(text.trim().replace('|', "\\|"));
+                }
+            }
+            BlockKind::Pre => {}
+        }
+    }
+
+    fn flush_table(&mut self) {
+        let rows = match self.table.take() {
+            Some(x) => x,
+            None => return,
+        };
+        let columns = rows.iter().map(|x| x.len()).max().unwrap_or(0);
+        if columns == 0 {
+            return;
+        }
+        // A table can't continue a paragraph
+        if self.lines.last().map(|x| !x.is_empty()).unwrap_or(false) {
+            self.lines.push(String::new());
+        }
+        for (index, row) in rows.iter().enumerate() {
+            let mut cells = row.clone();
+            cells.resize(columns, String::new());
+            self.lines.push(format!("| {} |", cells.join(" | ")));
+            if index == 0 {
+                let separator: String = (0..columns)
+                    .map(|x| match self.table_alignments.get(x).map(|x| x.as_str()) {
+                        Some("left") => " :--- |",
+                        Some("center") => " :---: |",
+                        Some("right") => " ---: |",
+                        _ => " --- |",
+                    })
+                    .collect();
+                self.lines.push(format!("|{}", separator));
+            }
+        }
+        self.lines.push(String::new());
+    }
+
+    // Qt puts the alignment of a cell on its paragraph
+    fn cell_alignment(&mut self, attrs: &HashMap<String, String>) {
+        let first_row = self.table.as_ref().map(|x| x.len() == 1).unwrap_or(false);
+        if let (true, Some(align)) = (first_row, attrs.get("align")) {
+            let column = self.table.as_ref().unwrap()[0].len();
+            if self.table_alignments.len() <= column {
+                self.table_alignments.resize(column + 1, String::new());
+            }
+            self.table_alignments[column] = align.to_lowercase();
+        }
+    }
+
+    fn open(&mut self, name: &str, attrs: &HashMap<String, String>) {
+        let style = attrs.get("style").map(|x| x.as_str()).unwrap_or("");
+        let block_indent = || {
+            Regex::new(r"-qt-block-indent:\s*(\d+)")
+                .unwrap()
+                .captures(style)
+                .and_then(|x| x[1].parse::<usize>().ok())
+                .unwrap_or(0)
+        };
+
+        match name {
+            "body" => self.in_body = true,
+            _ if !self.in_body => {}
+            "p" if self.table.is_some() => {
+                self.cell_alignment(attrs);
+                if self.block != Some(BlockKind::Cell) {
+                    self.start_block(BlockKind::Cell);
+                } else {
+                    self.inlines.push(Inline::Break);
+                }
+            }
+            "p" if style.contains("-qt-paragraph-type:empty") => {
+                self.start_block(BlockKind::Empty(block_indent()))
+            }
+            "p" | "div" | "blockquote" => self.start_block(BlockKind::Paragraph(block_indent())),
+            "h1" | "h2" | "h3" | "h4" | "h5" | "h6" => {
+                self.start_block(BlockKind::Heading(name[1..].parse().unwrap_or(1)))
+            }
+            "pre" => {
+                self.start_block(BlockKind::Pre);
+                // Lines after the first are the same block
+                if self.code_lines.is_empty() {
+                    self.code_language = attrs
+                        .get("class")
+                        .and_then(|x| {
+                            x.split_whitespace()
+                                .find_map(|x| x.strip_prefix("language-"))
+                        })
+                        .unwrap_or_default()
+                        .to_string();
+                }
+            }
+            "hr" => {
+                self.finish_block();
+                self.flush_code();
+                // *** can't turn the line before into a heading like --- can
+                self.lines.push("***".to_string());
+            }
+            "ul" | "ol" => {
+                self.finish_block();
+                let start = attrs
+                    .get("start")
+                    .and_then(|x| x.parse::<u64>().ok())
+                    .unwrap_or(1);
+                self.lists.push((name == "ol", start));
+            }
+            "li" => {
+                let depth = self.lists.len().max(1);
+                self.list_indents.truncate(depth - 1);
+                let indent = self.list_indents.concat();
+                let mut marker = match self.lists.last_mut() {
+                    Some((true, number)) => {
+                        *number += 1;
+                        format!("{}. ", *number - 1)
+                    }
+                    _ => "- ".to_string(),
+                };
+                // Nested lists go under the text of the item, not under its marker
+                self.list_indents.push(" ".repeat(marker.len()));
+                match attrs.get("class").map(|x| x.as_str()) {
+                    Some("checked") => marker.push_str("[x] "),
+                    Some("unchecked") => marker.push_str("[ ] "),
+                    _ => {}
+                }
+                let continuation = format!("{}{}", indent, " ".repeat(marker.len()));
+                self.start_block(BlockKind::Item(
+                    format!("{}{}", indent, marker),
+                    continuation,
+                ));
+            }
+            "table" => {
+                self.finish_block();
+                self.flush_code();
+                self.table = Some(Vec::new());
+                self.table_alignments.clear();
+            }
+            "tr" => {
+                if let Some(table) = self.table.as_mut() {
+                    table.push(Vec::new());
+                }
+            }
+            "td" | "th" => {
+                self.start_block(BlockKind::Cell);
+                self.cell_alignment(attrs);
+            }
+            "br" => self.inlines.push(Inline::Break),
+            "img" => {
+                let src = attrs.get("src").cloned().unwrap_or_default();
+                let alt = attrs.get("alt").cloned().unwrap_or_default();
+                let src = if src.contains(' ') {
+                    format!("<{}>", src)
+                } else {
+                    src
+                };
+                self.inlines.push(Inline::Raw(format!(
+                    "![{}]({}{})",
+                    escape_md(&alt),
+                    src,
+                    md_title(attrs)
+                )));
+            }
+            "a" => {
+                if let Some(href) = attrs.get("href") {
+                    self.link = Some((href.clone(), md_title(attrs)));
+                    self.inlines.push(Inline::Raw("[".to_string()));
+                }
+            }
+            "span" | "font" => {
+                let format = Format::from_style(style, self.format());
+                self.formats.push(format);
+            }
+            "b" | "strong" | "i" | "em" | "s" | "del" | "strike" | "code" | "tt" => {
+                let mut format = self.format();
+                match name {
+                    "b" | "strong" => format.bold = true,
+                    "i" | "em" => format.italic = true,
+                    "s" | "del" | "strike" => format.strike = true,
+                    _ => format.code = true,
+                }
+                self.formats.push(format);
+            }
+            _ => debug!("Ignoring <{}> from FeatherNotes", name),
+        }
+    }
+
+    fn close(&mut self, name: &str) {
+        match name {
+            "p" | "div" | "blockquote" | "h1" | "h2" | "h3" | "h4" | "h5" | "h6" | "pre"
+                if self.table.is_none() =>
+            {
+                self.finish_block()
+            }
+            "li" | "td" | "th" => self.finish_block(),
+            "ul" | "ol" => {
+                self.finish_block();
+                self.lists.pop();
+                self.list_indents.truncate(self.lists.len());
+            }
+            "table" => {
+                self.finish_block();
+                self.flush_table();
+            }
+            "a" => {
+                if let Some((href, title)) = self.link.take() {
+                    self.inlines
+                        .push(Inline::Raw(format!("]({}{})", href, title)));
+                }
+            }
+            "span" | "font" | "b" | "strong" | "i" | "em" | "s" | "del" | "strike" | "code"
+            | "tt" => {
+                self.formats.pop();
+            }
+            _ => {}
+        }
+    }
+}
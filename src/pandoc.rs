@@ -3,92 +3,124 @@ use pandoc::OutputKind;
 use pandoc::PandocOutput::*;
 use rand::{distributions::Alphanumeric, Rng};
 use std::io::Write;
-use std::process::exit;
 use regex::Regex;
-use std::env;
+use crate::data_types::joplin::{resource_dirs, ResourceInfo};
+use crate::attachments::resource_data_uri;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use crate::converter::Converter;
+use crate::error::BridgeError;
+use crate::math::MathMode;
+
+pub struct PandocConverter {
+    pub math: MathMode,
+}
+
+impl Converter for PandocConverter {
+    fn md_to_html(&self, md: String) -> Result<String, BridgeError> {
+        convert_md_to_html(md, self.math)
+    }
+
+    fn html_to_md(&self, html: String) -> Result<String, BridgeError> {
+        convert_html_to_md(html)
+    }
+}
 
 // repair_md_katex is done when calling for the md file
-// fix_embedding_files_md too
-pub fn convert_md_to_html(str: String) -> String {
+// fix_embedding_files_md too, and render_math_images_md for MathMode::Image
+pub fn convert_md_to_html(str: String, math: MathMode) -> Result<String, BridgeError> {
     let mut pandoc = pandoc::new();
 
     pandoc.set_input(InputKind::Pipe(str));
     pandoc.set_output(OutputKind::Pipe);
     pandoc.set_output_format(pandoc::OutputFormat::Html, Vec::new());
 
-    let mut resource_path = String::new();
-
-    let joplin_env = env::var("JOPLIN_RESOURCE_PATH");
-    match joplin_env {
-        Ok(x) => {
-            debug!("Using custom joplin resource path: {}", x);
-            resource_path = x;
-        },
-        Err(_) => {
-            let home_env = env::var("HOME");
-            match home_env {
-                Ok(x) => {
-                    resource_path = x + "/.config/joplin-desktop/resources/";
-                    debug!("Using default resource path: {}", resource_path);
-                }
-                Err(_) => {
-                    debug!("Couldn't get HOME env variable, no resource path available");
-                }
-            }
+    // Images JoplinData::get_note_body couldn't embed are looked for here
+    let resource_paths = resource_dirs().into_iter().collect();
+    pandoc.add_option(pandoc::PandocOption::ResourcePath(resource_paths));
+
+    // Tex and Image leave pandoc's \( \) spans, Image ones are already pictures by now
+    match math {
+        MathMode::Mathml => {
+            pandoc.add_option(pandoc::PandocOption::MathML(None));
+        }
+        MathMode::Webtex => {
+            pandoc.add_option(pandoc::PandocOption::WebTex(None));
         }
+        MathMode::Tex | MathMode::Image => {}
     }
-
-    pandoc.add_option(pandoc::PandocOption::ResourcePath(vec!(resource_path.into())));
-
-    pandoc.add_option(pandoc::PandocOption::WebTex(None));
     pandoc.add_option(pandoc::PandocOption::SelfContained);
     pandoc.add_option(pandoc::PandocOption::Standalone);
 
-    pandoc.set_input_format(pandoc::InputFormat::Commonmark, vec!(pandoc::MarkdownExtension::TexMathDollars));
-    let y = pandoc.execute().unwrap();
-    match y {
+    pandoc.set_input_format(pandoc::InputFormat::Commonmark, vec!(pandoc::MarkdownExtension::TexMathDollars, task_lists()));
+    match run_pandoc(pandoc)? {
         ToBuffer(x) => {
-            // Feather notes replaces this because of xml and html merging
-            let mut x_fixed = x.replace("<", "&lt;");
-            // This doesnt work here: x_fixed = x_fixed.replace("&amp;", "&"); // IDK?
+            let x_fixed = checkboxes_to_qt(&x);
             write_debug_file("", x_fixed.clone(), ".html");
-            return x_fixed;
+            Ok(x_fixed)
         }
-        _ => error!("Pandoc error!"),
+        _ => Err(BridgeError::Conversion("Pandoc didn't return the html".into())),
     }
-    exit(-1);
 }
 
-pub fn convert_html_to_md(str: String) -> String {
+pub fn convert_html_to_md(str: String) -> Result<String, BridgeError> {
     let mut pandoc = pandoc::new();
 
-    let mut str_converted = str.replace("&lt;", "<");
+    let str_converted = checkboxes_from_qt(&str);
 
     pandoc.set_input(InputKind::Pipe(str_converted));
     pandoc.set_output(OutputKind::Pipe);
-    pandoc.set_output_format(pandoc::OutputFormat::Commonmark, Vec::new());
+    pandoc.set_output_format(pandoc::OutputFormat::Commonmark, vec!(task_lists()));
     pandoc.set_input_format(pandoc::InputFormat::Html, Vec::new());
-    let y = pandoc.execute().unwrap();
-    match y {
+    match run_pandoc(pandoc)? {
         ToBuffer(x) => {
             write_debug_file("", x.clone(), ".md");
-            return x;
+            Ok(x)
         }
-        _ => error!("Pandoc error!"),
+        _ => Err(BridgeError::Conversion("Pandoc didn't return the markdown".into())),
     }
-    exit(-1);
 }
 
-// For a good reason quick_xml::se::to_string converts & to &amp;. Here we need to avoid that because html thing
-pub fn final_touches_xml(mut xml: String) -> String{
-    xml = (*xml.replace("&amp;", "&")).to_string();
+// A missing pandoc binary ends up here too
+fn run_pandoc(pandoc: pandoc::Pandoc) -> Result<pandoc::PandocOutput, BridgeError> {
+    pandoc
+        .execute()
+        .map_err(|x| BridgeError::Conversion(format!("Pandoc failed: {}", x)))
+}
 
-    xml
+fn task_lists() -> pandoc::MarkdownExtension {
+    pandoc::MarkdownExtension::Other("task_lists".to_string())
 }
 
+// Pandoc writes - [x] as a checkbox input, FeatherNotes wants li.checked / li.unchecked
+fn checkboxes_to_qt(html: &str) -> String {
+    let re_checkbox = Regex::new(r#"<li>\s*(?:<label>)?\s*<input type="checkbox"([^>]*)/?>"#).unwrap();
+    re_checkbox
+        .replace_all(html, |cap: &regex::Captures| {
+            if cap[1].contains("checked") {
+                "<li class=\"checked\">".to_string()
+            } else {
+                "<li class=\"unchecked\">".to_string()
+            }
+        })
+        .to_string()
+}
+
+// And back, pandoc knows task list items by the box characters it uses for them itself
+fn checkboxes_from_qt(html: &str) -> String {
+    let re_checked = Regex::new(r#"<li class="(checked|unchecked)"([^>]*)>"#).unwrap();
+    re_checked
+        .replace_all(html, |cap: &regex::Captures| {
+            let box_char = if &cap[1] == "checked" { "☒" } else { "☐" };
+            format!("<li{}>{} ", &cap[2], box_char)
+        })
+        .to_string()
+}
+
+
 pub fn write_debug_file(title: &str, content: String, extension: &str) {
     if log_enabled!(log::Level::Debug) {
-        let mut file_name = String::new();
+        let mut file_name: String;
 
         if title.is_empty() {
             let r: String = rand::thread_rng()
@@ -105,9 +137,13 @@ pub fn write_debug_file(title: &str, content: String, extension: &str) {
         file_name += extension;
 
         debug!("Writing file {} with body in it", file_name);
-        std::fs::remove_file(file_name.clone()); // no unwrap
-        let mut file = std::fs::File::create(file_name).unwrap();
-        file.write_all(content.as_bytes()).unwrap();
+        std::fs::remove_file(file_name.clone()).ok(); // no unwrap
+        // Only a debugging aid, a missing convert_tests dir mustn't stop the conversion
+        let written = std::fs::File::create(&file_name)
+            .and_then(|mut file| file.write_all(content.as_bytes()));
+        if let Err(x) = written {
+            warn!("Couldn't write debug file {}: {}", file_name, x);
+        }
     }
 }
 
@@ -120,6 +156,8 @@ pub fn repair_md_katex(md: String) -> String {
     // ChatGPT:
     // (?<!\$)\${1,2}([\s\S]*?)(?<!\\)\${1,2}(?!\$) also (?s)\$\$.*?\$\$|\$.*?\$
     let re_math = Regex::new(r"(?s)\$\$.*?\$\$|\$.*?\$").unwrap();
+    // \s+$
+    let re_spaces = Regex::new(r"\s+$").unwrap();
 
     for cap in re_math.captures_iter(&md) {
         debug!("Captured katex: {:#?}", cap);
@@ -138,9 +176,6 @@ pub fn repair_md_katex(md: String) -> String {
             str_katex = str_katex.split_at(str_katex.len() - 1).0;
         }
 
-        // \s+$
-        let re_spaces = Regex::new(r"\s+$").unwrap();
-
         let mut str_katex_cleaner = re_spaces.replace_all(str_katex, "").to_string();
 
         if double_dollars {
@@ -163,24 +198,32 @@ pub fn repair_md_katex(md: String) -> String {
 }
 
 // from
-// ![faab2f35c4ac06f929bc1eb700ecd731.png](:/060a68f375f0417aad4a8f02e9f6572fz)
+// ![faab2f35c4ac06f929bc1eb700ecd731.png](:/060a68f375f0417aad4a8f02e9f6572f)
 // to
-// ![faab2f35c4ac06f929bc1eb700ecd731.png](060a68f375f0417aad4a8f02e9f6572f.png)
-pub fn fix_embedding_files_md(md: String) -> String {
-    let mut converted_md = md.clone();
-    // https://regexr.com/4pk6v
-    // !\[(.*)\]\((.+)\)
-    let re_files = Regex::new(r"!\[(.*)\]\((.+)\)").unwrap();
-    for cap in re_files.captures_iter(&md) {
-        debug!("Captured file: {:#?}", cap);
-        let mut str_file: String = cap[0].to_string();
-        // TODO is it sure?
-        str_file = str_file.replace("](:/", "](");
-        str_file = str_file.replace(")", ".png)");
-        debug!("Fixed file: {}", str_file);
-
-        converted_md = converted_md.replace(&cap[0], &str_file);
-    }
-
-    converted_md
+// ![faab2f35c4ac06f929bc1eb700ecd731.png](data:image/jpeg;joplin-resource=060a68f375f0417aad4a8f02e9f6572f;base64,...)
+// so both converters get the same, and the way back knows the resource it came from.
+// Without the file it's the name joplin knows it by, for the resource dirs. Links to anything else are left alone
+pub fn fix_embedding_files_md(md: String, resources: &[ResourceInfo], files: &HashMap<String, PathBuf>) -> String {
+    let re_files = Regex::new(r"!\[([^\]]*)\]\(:/([0-9a-fA-F]{32})\)").unwrap();
+    re_files
+        .replace_all(&md, |cap: &regex::Captures| {
+            debug!("Captured file: {:#?}", cap);
+            let fixed = match resources.iter().find(|x| x.id == cap[2]) {
+                Some(resource) => match files.get(&resource.id).map(std::fs::read) {
+                    Some(Ok(bytes)) => format!("![{}]({})", &cap[1], resource_data_uri(resource, &bytes)),
+                    Some(Err(x)) => {
+                        warn!("Couldn't read resource {}: {}", resource.id, x);
+                        format!("![{}]({})", &cap[1], resource.file_name())
+                    }
+                    None => format!("![{}]({})", &cap[1], resource.file_name()),
+                },
+                None => {
+                    warn!("Resource {} isn't attached to the note, guessing png", &cap[2]);
+                    format!("![{}]({}.png)", &cap[1], &cap[2])
+                }
+            };
+            debug!("Fixed file: {:.80}", fixed);
+            fixed
+        })
+        .to_string()
 }
@@ -0,0 +1,116 @@
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PlanFormat {
+    Tree,
+    Json,
+}
+
+// One thing a run does, or would do with --dry-run
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct PlanStep {
+    pub target: String, // "feather", "joplin" or "file"
+    pub action: String,
+    pub path: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+#[derive(Serialize, Debug, Default)]
+pub struct Plan {
+    pub dry_run: bool,
+    pub steps: Vec<PlanStep>,
+}
+
+// Path components to steps, so the plan can be printed like the notebook looks
+#[derive(Default)]
+struct PlanTree<'a> {
+    steps: Vec<&'a PlanStep>,
+    children: BTreeMap<&'a str, PlanTree<'a>>,
+}
+
+impl Plan {
+    pub fn new(dry_run: bool) -> Plan {
+        Plan {
+            dry_run,
+            steps: Vec::new(),
+        }
+    }
+
+    pub fn add(&mut self, target: &str, action: &str, path: &[String], detail: Option<String>) {
+        debug!("Plan: {} {} {:?}", target, action, path);
+        self.steps.push(PlanStep {
+            target: target.to_string(),
+            action: action.to_string(),
+            path: path.to_vec(),
+            detail,
+        });
+    }
+
+    pub fn render(&self, format: PlanFormat) -> String {
+        match format {
+            PlanFormat::Json => serde_json::to_string_pretty(self).unwrap(),
+            PlanFormat::Tree => self.render_tree(),
+        }
+    }
+
+    fn render_tree(&self) -> String {
+        let mut root = PlanTree::default();
+        let mut files: Vec<&PlanStep> = Vec::new();
+        for step in &self.steps {
+            if step.path.is_empty() {
+                files.push(step);
+                continue;
+            }
+            let mut tree = &mut root;
+            for name in &step.path {
+                tree = tree.children.entry(name.as_str()).or_default();
+            }
+            tree.steps.push(step);
+        }
+
+        let mut out = String::new();
+        if self.dry_run {
+            out.push_str("Dry run, nothing was changed. The run would:\n");
+        }
+        if root.children.is_empty() && files.is_empty() {
+            out.push_str("Nothing to do\n");
+        }
+        render_children(&root, "", &mut out);
+        for step in files {
+            out.push_str(&format!("{}: {}", step.target, step.action));
+            if let Some(detail) = &step.detail {
+                out.push_str(&format!(" {}", detail));
+            }
+            out.push('\n');
+        }
+        out
+    }
+}
+
+fn render_children(tree: &PlanTree, prefix: &str, out: &mut String) {
+    let count = tree.children.len();
+    for (index, (name, child)) in tree.children.iter().enumerate() {
+        let last = index + 1 == count;
+        let steps: Vec<String> = child
+            .steps
+            .iter()
+            .map(|x| match &x.detail {
+                Some(detail) => format!("{}: {} ({})", x.target, x.action, detail),
+                None => format!("{}: {}", x.target, x.action),
+            })
+            .collect();
+
+        out.push_str(prefix);
+        out.push_str(if last { "└── " } else { "├── " });
+        out.push_str(name);
+        if !steps.is_empty() {
+            out.push_str(&format!(" [{}]", steps.join(", ")));
+        }
+        out.push('\n');
+
+        let child_prefix = format!("{}{}", prefix, if last { "    " } else { "│   " });
+        render_children(child, &child_prefix, out);
+    }
+}
@@ -0,0 +1,1026 @@
+use crate::convert_logic::{feather_note_to_joplin, is_joplin_id};
+use crate::data_types::feather::*;
+use crate::data_types::joplin::*;
+use crate::error::BridgeError;
+use crate::plan::Plan;
+use crate::tags::{apply_node_tag, build_tags_tree};
+use crate::todo::{feather_name, todo_fields};
+use crate::util::hash_body;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::{BTreeMap, HashSet};
+use std::path::Path;
+
+// One synced item, joplin note or folder and the feather node it was written to
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SyncItem {
+    pub joplin_id: String,
+    pub is_folder: bool,
+    pub path: Vec<String>, // Node names from root, the last one is the item itself
+    pub joplin_updated_time: i64,
+    pub feather_hash: String,
+}
+
+// Sidecar file next to the .fnx, remembers how things looked after the last sync
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct SyncState {
+    pub items: Vec<SyncItem>,
+}
+
+// A broken state file is a file problem, not one of joplin
+fn state_error(path: &str, x: serde_json::Error) -> BridgeError {
+    BridgeError::Io(std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        format!("Sync state {}: {}", path, x),
+    ))
+}
+
+impl SyncState {
+    pub fn state_path(feather_path: &str) -> String {
+        format!("{}.sync.json", feather_path)
+    }
+
+    pub fn load(path: &str) -> Result<SyncState, BridgeError> {
+        if !Path::new(path).exists() {
+            info!("No sync state at {}, this is the first sync", path);
+            return Ok(SyncState::default());
+        }
+        debug!("Reading sync state from {}", path);
+        serde_json::from_str(&std::fs::read_to_string(path)?).map_err(|x| state_error(path, x))
+    }
+
+    pub fn save(&self, path: &str) -> Result<(), BridgeError> {
+        info!("Writing sync state to {}", path);
+        let json = serde_json::to_string_pretty(self).map_err(|x| state_error(path, x))?;
+        replace_file(Path::new(path), json.as_bytes(), 0)
+    }
+
+    pub fn find(&self, joplin_id: &str) -> Option<&SyncItem> {
+        self.items.iter().find(|x| x.joplin_id == joplin_id)
+    }
+
+    pub fn find_by_path(&self, path: &[String]) -> Option<&SyncItem> {
+        self.items.iter().find(|x| x.path == path)
+    }
+
+    pub fn remove(&mut self, joplin_id: &str) {
+        self.items.retain(|x| x.joplin_id != joplin_id);
+    }
+
+    // Children follow their parent
+    pub fn move_path(&mut self, from: &[String], to: &[String]) {
+        for item in self.items.iter_mut() {
+            if item.path.starts_with(from) {
+                let mut new_path = to.to_vec();
+                new_path.extend_from_slice(&item.path[from.len()..]);
+                item.path = new_path;
+            }
+        }
+    }
+
+    pub fn set(&mut self, item: SyncItem) {
+        match self
+            .items
+            .iter_mut()
+            .find(|x| x.joplin_id == item.joplin_id)
+        {
+            Some(x) => *x = item,
+            None => self.items.push(item),
+        }
+    }
+}
+
+// Paths in actions are where things are when the action gets applied, earlier moves included
+#[derive(Debug, Clone, PartialEq)]
+pub enum SyncAction {
+    Unchanged {
+        path: Vec<String>,
+    },
+    NewInJoplin {
+        joplin_id: String,
+        is_folder: bool,
+        path: Vec<String>,
+    },
+    NewInFeather {
+        is_folder: bool,
+        path: Vec<String>,
+    },
+    ChangedInJoplin {
+        joplin_id: String,
+        path: Vec<String>,
+    },
+    ChangedInFeather {
+        joplin_id: String,
+        path: Vec<String>,
+    },
+    ChangedInBoth {
+        joplin_id: String,
+        path: Vec<String>,
+    },
+    MovedInJoplin {
+        joplin_id: String,
+        from: Vec<String>,
+        to: Vec<String>,
+    },
+    MovedInFeather {
+        joplin_id: String,
+        is_folder: bool,
+        to: Vec<String>,
+    },
+    DeletedInJoplin {
+        joplin_id: String,
+        path: Vec<String>,
+    },
+    DeletedInFeather {
+        joplin_id: String,
+        is_folder: bool,
+        path: Vec<String>,
+    },
+    DeletedInBoth {
+        joplin_id: String,
+        path: Vec<String>,
+    },
+}
+
+fn folder_path_titles(
+    joplin_struct: &JoplinData,
+    folder_id: &str,
+) -> Result<Vec<String>, BridgeError> {
+    Ok(joplin_struct
+        .get_path_folder(folder_id)?
+        .into_iter()
+        .map(|x| x.title)
+        .collect())
+}
+
+// Moving a node moves everything below it, applied in the order they happened
+fn rewrite_path(path: &[String], moves: &[(Vec<String>, Vec<String>)]) -> Vec<String> {
+    let mut path = path.to_vec();
+    for (from, to) in moves {
+        if path.starts_with(from) {
+            let mut new_path = to.clone();
+            new_path.extend_from_slice(&path[from.len()..]);
+            path = new_path;
+        }
+    }
+    path
+}
+
+// Where did the node of a synced item go in the feather file. Folders written by the bridge
+// carry their joplin id as text, notes are recognised by their unchanged body
+fn find_in_feather(
+    nodes: &[Node],
+    node_paths: &[(Vec<String>, &Node)],
+    item: &SyncItem,
+    feather_moves: &[(Vec<String>, Vec<String>)],
+    taken: &HashSet<Vec<String>>,
+) -> Result<Option<Vec<String>>, BridgeError> {
+    if node_at_path(nodes, &item.path).is_some() {
+        return Ok(Some(item.path.clone()));
+    }
+    let moved_with_parent = rewrite_path(&item.path, feather_moves);
+    if node_at_path(nodes, &moved_with_parent).is_some() {
+        return Ok(Some(moved_with_parent));
+    }
+
+    let mut candidates: Vec<&Vec<String>> = Vec::new();
+    for (path, node) in node_paths {
+        if taken.contains(path) {
+            continue;
+        }
+        let text = node.text.as_str()?;
+        let found = if item.is_folder {
+            text.trim() == item.joplin_id
+        } else {
+            hash_body(text) == item.feather_hash
+        };
+        if found {
+            candidates.push(path);
+        }
+    }
+
+    match candidates.len() {
+        1 => Ok(Some(candidates[0].clone())),
+        0 => Ok(None),
+        _ => {
+            warn!(
+                "{:?} could be any of {:?}, treating it as deleted",
+                item.path, candidates
+            );
+            Ok(None)
+        }
+    }
+}
+
+pub fn classify(
+    feather_file: &FeatherStruct,
+    joplin_struct: &JoplinData,
+    joplin_folders: &[FoldersArray],
+    state: &SyncState,
+    scope: &[String],
+    skip_roots: &[&str],
+) -> Result<Vec<SyncAction>, BridgeError> {
+    let mut actions: Vec<SyncAction> = Vec::new();
+    let nodes = &feather_file.struct_xml.node;
+
+    let mut node_paths: Vec<(Vec<String>, &Node)> = Vec::new();
+    collect_node_paths(nodes, &[], &mut node_paths);
+
+    // Nodes that belong to some synced item, they can't be a moved one
+    let mut taken: HashSet<Vec<String>> = state
+        .items
+        .iter()
+        .filter(|x| node_at_path(nodes, &x.path).is_some())
+        .map(|x| x.path.clone())
+        .collect();
+    // Feather paths of synced items as they are now in the file
+    let mut claimed: HashSet<Vec<String>> = HashSet::new();
+
+    let mut feather_moves: Vec<(Vec<String>, Vec<String>)> = Vec::new(); // Found in the file, old -> new
+    let mut tree_moves: Vec<(Vec<String>, Vec<String>)> = Vec::new(); // Done to the file by earlier actions
+    let mut state_moves: Vec<(Vec<String>, Vec<String>)> = Vec::new(); // Done to the state by earlier actions
+    let mut joplin_moves: Vec<(Vec<String>, Vec<String>)> = Vec::new(); // Done to joplin by earlier actions
+    let mut deleted_in_feather: Vec<Vec<String>> = Vec::new();
+    let mut deleted_in_joplin: Vec<Vec<String>> = Vec::new();
+
+    // Parents first, so their moves are known before children are looked at
+    let mut items: Vec<&SyncItem> = state
+        .items
+        .iter()
+        .filter(|x| x.path.starts_with(scope))
+        .collect();
+    items.sort_by_key(|x| x.path.len());
+
+    for item in items {
+        let state_path = rewrite_path(&item.path, &state_moves);
+
+        let original_path = find_in_feather(nodes, &node_paths, item, &feather_moves, &taken)?;
+        if let Some(location) = &original_path {
+            claimed.insert(location.clone());
+            taken.insert(location.clone());
+            if item.is_folder && location != &item.path {
+                feather_moves.push((item.path.clone(), location.clone()));
+            }
+        }
+        let tree_path = original_path.as_ref().map(|x| rewrite_path(x, &tree_moves));
+
+        let joplin_path: Option<(Vec<String>, i64)> = if item.is_folder {
+            match joplin_struct.folder(&item.joplin_id) {
+                Some(folder) => Some((
+                    folder_path_titles(joplin_struct, &folder.id)?,
+                    folder.updated_time,
+                )),
+                None => None,
+            }
+        } else {
+            match joplin_struct.note(&item.joplin_id) {
+                Some(note) => {
+                    let mut path = folder_path_titles(joplin_struct, &note.parent_id)?;
+                    path.push(feather_name(note));
+                    Some((path, note.updated_time))
+                }
+                None => None,
+            }
+        };
+
+        // A folder moved in feather takes what is in it along once it is moved in joplin
+        let joplin_path =
+            joplin_path.map(|(path, time)| (rewrite_path(&path, &joplin_moves), time));
+
+        let joplin_id = item.joplin_id.clone();
+        let (tree_path, (joplin_path, joplin_updated_time)) = match (tree_path, joplin_path) {
+            (None, None) => {
+                actions.push(SyncAction::DeletedInBoth {
+                    joplin_id,
+                    path: state_path,
+                });
+                continue;
+            }
+            (Some(path), None) => {
+                if deleted_in_joplin.iter().any(|x| path.starts_with(x)) {
+                    // Went away with its parent
+                    actions.push(SyncAction::DeletedInBoth { joplin_id, path });
+                } else {
+                    deleted_in_joplin.push(path.clone());
+                    actions.push(SyncAction::DeletedInJoplin { joplin_id, path });
+                }
+                continue;
+            }
+            (None, Some((joplin_path, joplin_updated_time))) => {
+                if deleted_in_feather.iter().any(|x| state_path.starts_with(x)) {
+                    actions.push(SyncAction::DeletedInBoth {
+                        joplin_id,
+                        path: state_path,
+                    });
+                } else if joplin_updated_time != item.joplin_updated_time {
+                    // Deleting something that was just edited would lose the edit, bring it back
+                    warn!(
+                        "{:?} was deleted in feather but changed in joplin, restoring it",
+                        state_path
+                    );
+                    actions.push(SyncAction::NewInJoplin {
+                        joplin_id,
+                        is_folder: item.is_folder,
+                        path: joplin_path,
+                    });
+                } else {
+                    deleted_in_feather.push(state_path.clone());
+                    actions.push(SyncAction::DeletedInFeather {
+                        joplin_id,
+                        is_folder: item.is_folder,
+                        path: state_path,
+                    });
+                }
+                continue;
+            }
+            (Some(tree_path), Some(joplin)) => (tree_path, joplin),
+        };
+
+        let moved_in_joplin = joplin_path != state_path;
+        let moved_in_feather = tree_path != state_path;
+        let path = if moved_in_joplin {
+            if moved_in_feather {
+                warn!(
+                    "{:?} was moved both in joplin and feather, joplin wins",
+                    state_path
+                );
+            }
+            if tree_path != joplin_path {
+                actions.push(SyncAction::MovedInJoplin {
+                    joplin_id: joplin_id.clone(),
+                    from: tree_path.clone(),
+                    to: joplin_path.clone(),
+                });
+                tree_moves.push((tree_path, joplin_path.clone()));
+            }
+            state_moves.push((state_path, joplin_path.clone()));
+            joplin_path
+        } else if moved_in_feather {
+            actions.push(SyncAction::MovedInFeather {
+                joplin_id: joplin_id.clone(),
+                is_folder: item.is_folder,
+                to: tree_path.clone(),
+            });
+            state_moves.push((state_path, tree_path.clone()));
+            if item.is_folder {
+                joplin_moves.push((joplin_path, tree_path.clone()));
+            }
+            tree_path
+        } else {
+            tree_path
+        };
+
+        if item.is_folder {
+            if !moved_in_joplin && !moved_in_feather {
+                actions.push(SyncAction::Unchanged { path });
+            }
+            continue;
+        }
+
+        // The body is looked at in the file as it was read
+        let text = match original_path.and_then(|x| node_at_path(nodes, &x)) {
+            Some(node) => node.text.as_str()?,
+            None => "",
+        };
+
+        let joplin_changed = joplin_updated_time != item.joplin_updated_time;
+        let feather_changed = hash_body(text) != item.feather_hash;
+
+        actions.push(match (joplin_changed, feather_changed) {
+            (false, false) => SyncAction::Unchanged { path },
+            (true, false) => SyncAction::ChangedInJoplin { joplin_id, path },
+            (false, true) => SyncAction::ChangedInFeather { joplin_id, path },
+            (true, true) => SyncAction::ChangedInBoth { joplin_id, path },
+        });
+    }
+
+    // Things joplin got since the last sync, parents need to exist before their children
+    let mut folder_paths: Vec<(&FoldersArray, Vec<String>)> = Vec::new();
+    for folder in joplin_folders {
+        if state.find(&folder.id).is_some() {
+            continue;
+        }
+        let path = folder_path_titles(joplin_struct, &folder.id)?;
+        folder_paths.push((folder, path));
+    }
+    folder_paths.sort_by_key(|x| x.1.len());
+
+    for (folder, path) in folder_paths {
+        actions.push(SyncAction::NewInJoplin {
+            joplin_id: folder.id.clone(),
+            is_folder: true,
+            path,
+        });
+    }
+
+    for folder in joplin_folders {
+        for note in joplin_struct
+            .child_notes(&folder.id)
+            .filter(|x| state.find(&x.id).is_none())
+        {
+            let mut path = folder_path_titles(joplin_struct, &folder.id)?;
+            path.push(feather_name(note));
+            actions.push(SyncAction::NewInJoplin {
+                joplin_id: note.id.clone(),
+                is_folder: false,
+                path,
+            });
+        }
+    }
+
+    // Everything in the feather file that was never synced, only below the requested folder
+    let new_in_joplin_paths: Vec<Vec<String>> = actions
+        .iter()
+        .filter_map(|x| match x {
+            SyncAction::NewInJoplin { path, .. } => Some(path.clone()),
+            _ => None,
+        })
+        .collect();
+
+    for (path, node) in node_paths {
+        if path.len() <= scope.len() || !path.starts_with(scope) {
+            continue;
+        }
+        // Trash and tags trees are made by the bridge
+        if skip_roots.contains(&path[0].as_str()) {
+            continue;
+        }
+        if claimed.contains(&path) || new_in_joplin_paths.contains(&path) {
+            continue;
+        }
+        let path = rewrite_path(&path, &tree_moves);
+        actions.push(SyncAction::NewInFeather {
+            is_folder: !node.node.is_empty() || is_joplin_id(node.text.as_str()?.trim()),
+            path,
+        });
+    }
+
+    Ok(actions)
+}
+
+fn joplin_updated_time(joplin_struct: &JoplinData, joplin_id: &str, is_folder: bool) -> i64 {
+    if is_folder {
+        joplin_struct
+            .folder(joplin_id)
+            .map(|x| x.updated_time)
+            .unwrap_or_default()
+    } else {
+        joplin_struct
+            .note(joplin_id)
+            .map(|x| x.updated_time)
+            .unwrap_or_default()
+    }
+}
+
+fn record(
+    state: &mut SyncState,
+    joplin_id: &str,
+    is_folder: bool,
+    path: &[String],
+    joplin_updated_time: i64,
+    text: &str,
+) {
+    state.set(SyncItem {
+        joplin_id: joplin_id.to_string(),
+        is_folder,
+        path: path.to_vec(),
+        joplin_updated_time,
+        // The file trims bodies, pandoc ends them with a newline
+        feather_hash: hash_body(trim_body(text)),
+    });
+}
+
+fn joplin_to_feather_body(
+    joplin_struct: &JoplinData,
+    joplin_id: &str,
+) -> Result<String, BridgeError> {
+    joplin_struct.get_note_html(joplin_id)
+}
+
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConflictStrategy {
+    PreferJoplin,
+    PreferFeather,
+    NewestWins,
+    KeepBoth, // The feather version goes into a sibling "<title> (conflict YYYY-MM-DD)" on both sides
+}
+
+pub struct SyncOptions {
+    pub conflict: ConflictStrategy,
+    pub feather_modified_time: i64, // Nodes have no time, so the file one it is. Milliseconds like joplin
+    pub trash_node: Option<String>, // Root node for things deleted in joplin, if not set they are removed
+    pub tags_tree: Option<String>,  // Root node made from joplin tags, never synced
+    pub backups: usize,             // Backups of the old output file to keep
+}
+
+// What happened, to be shown at the end of the run
+#[derive(Debug, Default)]
+pub struct SyncSummary {
+    pub counts: BTreeMap<&'static str, usize>,
+    pub conflicts: Vec<String>,
+}
+
+impl SyncSummary {
+    pub fn count(&mut self, action: &SyncAction) {
+        let name = match action {
+            SyncAction::Unchanged { .. } => "unchanged",
+            SyncAction::NewInJoplin { .. } => "new in joplin",
+            SyncAction::NewInFeather { .. } => "new in feather",
+            SyncAction::ChangedInJoplin { .. } => "changed in joplin",
+            SyncAction::ChangedInFeather { .. } => "changed in feather",
+            SyncAction::ChangedInBoth { .. } => "changed in both",
+            SyncAction::MovedInJoplin { .. } => "moved in joplin",
+            SyncAction::MovedInFeather { .. } => "moved in feather",
+            SyncAction::DeletedInJoplin { .. } => "deleted in joplin",
+            SyncAction::DeletedInFeather { .. } => "deleted in feather",
+            SyncAction::DeletedInBoth { .. } => "deleted in both",
+        };
+        *self.counts.entry(name).or_default() += 1;
+    }
+
+    pub fn conflict(&mut self, path: &[String], resolution: &str) {
+        let line = format!("{:?}: {}", path, resolution);
+        warn!("Conflict {}", line);
+        self.conflicts.push(line);
+    }
+
+    pub fn log(&self) {
+        info!("Sync done: {:?}", self.counts);
+        if !self.conflicts.is_empty() {
+            info!("{} conflicts were resolved:", self.conflicts.len());
+            for conflict in &self.conflicts {
+                info!("  {}", conflict);
+            }
+        }
+    }
+}
+
+fn pull_from_joplin(
+    nodes: &mut [Node],
+    joplin_struct: &JoplinData,
+    state: &mut SyncState,
+    joplin_id: &str,
+    path: &[String],
+) -> Result<(), BridgeError> {
+    let text = joplin_to_feather_body(joplin_struct, joplin_id)?;
+    let time = joplin_updated_time(joplin_struct, joplin_id, false);
+    if let Some(node) = node_at_path_mut(nodes, path) {
+        node.text = text.clone().into();
+        apply_node_tag(node, joplin_struct, joplin_id);
+    }
+    record(state, joplin_id, false, path, time, &text);
+    info!("Updated in feather: {:?}", path);
+    Ok(())
+}
+
+fn push_to_joplin(
+    nodes: &[Node],
+    joplin_struct: &mut JoplinData,
+    state: &mut SyncState,
+    joplin_id: &str,
+    path: &[String],
+) -> Result<(), BridgeError> {
+    let text = match node_at_path(nodes, path) {
+        Some(node) => node.text.as_str()?.to_string(),
+        None => return Ok(()),
+    };
+    let name = path.last().unwrap();
+    let item = feather_note_to_joplin(joplin_struct, name, &text, Some(joplin_id))?;
+    let time = joplin_struct.update_item("notes", joplin_id, item)?;
+    record(state, joplin_id, false, path, time, &text);
+    info!("Updated in joplin: {:?}", path);
+    Ok(())
+}
+
+// Joplin keeps the original, the feather version lands next to it on both sides
+fn keep_both(
+    nodes: &mut Vec<Node>,
+    joplin_struct: &mut JoplinData,
+    state: &mut SyncState,
+    joplin_id: &str,
+    path: &[String],
+) -> Result<Vec<String>, BridgeError> {
+    let feather_text = match node_at_path(nodes, path) {
+        Some(node) => node.text.as_str()?.to_string(),
+        None => return Ok(path.to_vec()),
+    };
+    let parent_id = joplin_struct
+        .note(joplin_id)
+        .map(|x| x.parent_id.clone())
+        .unwrap_or_default();
+
+    let title = format!(
+        "{} (conflict {})",
+        path.last().unwrap(),
+        chrono::Local::now().format("%Y-%m-%d")
+    );
+    let mut conflict_path = path.to_vec();
+    *conflict_path.last_mut().unwrap() = title.clone();
+
+    let item = feather_note_to_joplin(joplin_struct, &title, &feather_text, None)?;
+    let conflict_id = joplin_struct.create_note(item, &parent_id)?;
+    let time = joplin_struct.get_updated_time("notes", &conflict_id)?;
+
+    let mut texts = vec![String::new(); conflict_path.len() - 1];
+    texts.push(feather_text.clone());
+    ensure_node_at_path(nodes, &conflict_path, &texts);
+    record(
+        state,
+        &conflict_id,
+        false,
+        &conflict_path,
+        time,
+        &feather_text,
+    );
+
+    pull_from_joplin(nodes, joplin_struct, state, joplin_id, path)?;
+    Ok(conflict_path)
+}
+
+// Both sides have their own version of the note, --conflict says which one stays
+fn resolve_conflict(
+    nodes: &mut Vec<Node>,
+    joplin_struct: &mut JoplinData,
+    state: &mut SyncState,
+    joplin_id: &str,
+    path: &[String],
+    options: &SyncOptions,
+    summary: &mut SyncSummary,
+) -> Result<(), BridgeError> {
+    match options.conflict {
+        ConflictStrategy::PreferJoplin => {
+            pull_from_joplin(nodes, joplin_struct, state, joplin_id, path)?;
+            summary.conflict(path, "kept the joplin version");
+        }
+        ConflictStrategy::PreferFeather => {
+            push_to_joplin(nodes, joplin_struct, state, joplin_id, path)?;
+            summary.conflict(path, "kept the feather version");
+        }
+        ConflictStrategy::NewestWins => {
+            let joplin_time = joplin_updated_time(joplin_struct, joplin_id, false);
+            if joplin_time >= options.feather_modified_time {
+                pull_from_joplin(nodes, joplin_struct, state, joplin_id, path)?;
+                summary.conflict(path, "joplin version is newer, kept it");
+            } else {
+                push_to_joplin(nodes, joplin_struct, state, joplin_id, path)?;
+                summary.conflict(path, "feather version is newer, kept it");
+            }
+        }
+        ConflictStrategy::KeepBoth => {
+            let conflict_path = keep_both(nodes, joplin_struct, state, joplin_id, path)?;
+            summary.conflict(
+                path,
+                &format!("kept both, feather version is in {:?}", conflict_path),
+            );
+        }
+    }
+    Ok(())
+}
+
+// Joplin folder a node at path should be in, None if there isn't one
+fn parent_folder_id(state: &SyncState, path: &[String], scope_id: &str) -> Option<String> {
+    let parent_path = &path[..path.len() - 1];
+    match state.find_by_path(parent_path) {
+        Some(x) if x.is_folder => Some(x.joplin_id.clone()),
+        Some(_) => {
+            warn!("Parent of {:?} is a note, joplin can't nest notes", path);
+            None
+        }
+        None if parent_path.is_empty() => Some(scope_id.to_string()),
+        None => {
+            warn!("Parent of {:?} isn't synced", path);
+            None
+        }
+    }
+}
+
+pub fn apply(
+    action: &SyncAction,
+    feather_file: &mut FeatherStruct,
+    joplin_struct: &mut JoplinData,
+    state: &mut SyncState,
+    scope_id: &str,
+    options: &SyncOptions,
+    summary: &mut SyncSummary,
+) -> Result<(), BridgeError> {
+    let nodes = &mut feather_file.struct_xml.node;
+    match action {
+        SyncAction::Unchanged { .. } => {}
+        SyncAction::NewInJoplin {
+            joplin_id,
+            is_folder,
+            path,
+        } => {
+            let time = joplin_updated_time(joplin_struct, joplin_id, *is_folder);
+            if *is_folder {
+                if let Some(node) = node_at_path_mut(nodes, path) {
+                    info!("{:?} already exists in feather file, linking it", path);
+                    if node.text.as_str()?.trim().is_empty() {
+                        // So it can be found after being moved
+                        node.text = joplin_id.as_str().into();
+                    }
+                    record(state, joplin_id, true, path, time, node.text.as_str()?);
+                    return Ok(());
+                }
+            } else if let Some(node) = node_at_path(nodes, path) {
+                // Only the same note can be linked, a different one with the same name is a conflict
+                let text = node.text.as_str()?.to_string();
+                if trim_body(&joplin_to_feather_body(joplin_struct, joplin_id)?) == trim_body(&text)
+                {
+                    info!("{:?} already exists in feather file, linking it", path);
+                    record(state, joplin_id, false, path, time, &text);
+                    return Ok(());
+                }
+                return resolve_conflict(
+                    nodes,
+                    joplin_struct,
+                    state,
+                    joplin_id,
+                    path,
+                    options,
+                    summary,
+                );
+            }
+
+            let text = if *is_folder {
+                joplin_id.clone()
+            } else {
+                joplin_to_feather_body(joplin_struct, joplin_id)?
+            };
+            let mut texts = vec![String::new(); path.len() - 1];
+            texts.push(text.clone());
+            if let Some(node) = ensure_node_at_path(nodes, path, &texts) {
+                if !*is_folder {
+                    apply_node_tag(node, joplin_struct, joplin_id);
+                }
+            }
+
+            record(state, joplin_id, *is_folder, path, time, &text);
+            info!("Written to feather: {:?}", path);
+        }
+        SyncAction::ChangedInJoplin { joplin_id, path } => {
+            pull_from_joplin(nodes, joplin_struct, state, joplin_id, path)?;
+        }
+        SyncAction::ChangedInFeather { joplin_id, path } => {
+            push_to_joplin(nodes, joplin_struct, state, joplin_id, path)?;
+        }
+        SyncAction::ChangedInBoth { joplin_id, path } => {
+            resolve_conflict(
+                nodes,
+                joplin_struct,
+                state,
+                joplin_id,
+                path,
+                options,
+                summary,
+            )?;
+        }
+        SyncAction::NewInFeather { is_folder, path } => {
+            let parent_id = match parent_folder_id(state, path, scope_id) {
+                Some(x) => x,
+                None => {
+                    warn!("Not writing {:?} to joplin", path);
+                    return Ok(());
+                }
+            };
+            let text = match node_at_path(nodes, path) {
+                Some(node) => node.text.as_str()?.to_string(),
+                None => return Ok(()),
+            };
+            let title = path.last().unwrap();
+
+            let (joplin_id, item_type) = if *is_folder {
+                (joplin_struct.create_folder(title, &parent_id)?, "folders")
+            } else {
+                let item = feather_note_to_joplin(joplin_struct, title, &text, None)?;
+                (joplin_struct.create_note(item, &parent_id)?, "notes")
+            };
+            let mut text = text;
+            if *is_folder && text.trim().is_empty() {
+                // So it can be found after being moved, same as folders made from joplin
+                if let Some(node) = node_at_path_mut(nodes, path) {
+                    node.text = joplin_id.as_str().into();
+                }
+                text = joplin_id.clone();
+            }
+            let time = joplin_struct.get_updated_time(item_type, &joplin_id)?;
+            record(state, &joplin_id, *is_folder, path, time, &text);
+            info!("Written to joplin: {:?}", path);
+        }
+        SyncAction::MovedInJoplin {
+            joplin_id,
+            from,
+            to,
+        } => {
+            let mut node = match remove_node_at_path(nodes, from) {
+                Some(x) => x,
+                None => {
+                    warn!("{:?} is not in the feather file, can't move it", from);
+                    return Ok(());
+                }
+            };
+            node.name = to.last().unwrap().clone();
+            insert_node_at_path(nodes, &to[..to.len() - 1], node);
+
+            if let Some(item) = state.find(joplin_id) {
+                let old_path = item.path.clone();
+                state.move_path(&old_path, to);
+            }
+            info!("Moved in feather: {:?} -> {:?}", from, to);
+        }
+        SyncAction::MovedInFeather {
+            joplin_id,
+            is_folder,
+            to,
+        } => {
+            let old_path = match state.find(joplin_id) {
+                Some(x) => x.path.clone(),
+                None => return Ok(()),
+            };
+            let parent_id = match parent_folder_id(state, to, scope_id) {
+                Some(x) if !x.is_empty() || *is_folder => x,
+                _ => {
+                    warn!("Can't move {:?} to {:?} in joplin", old_path, to);
+                    return Ok(());
+                }
+            };
+            let name = to.last().unwrap();
+            let (item_type, mut item) = if *is_folder {
+                ("folders", json!({ "title": name }))
+            } else {
+                // Ticking a to-do in feather renames the node
+                let existing = joplin_struct.note(joplin_id);
+                ("notes", todo_fields(name, existing))
+            };
+            item["parent_id"] = json!(parent_id);
+            let time = joplin_struct.update_item(item_type, joplin_id, item)?;
+
+            state.move_path(&old_path, to);
+            if let Some(item) = state.find(joplin_id) {
+                let mut item = item.clone();
+                item.joplin_updated_time = time;
+                state.set(item);
+            }
+            info!("Moved in joplin: {:?} -> {:?}", old_path, to);
+        }
+        SyncAction::DeletedInJoplin { joplin_id, path } => {
+            let node = remove_node_at_path(nodes, path);
+            match (node, &options.trash_node) {
+                (Some(node), Some(trash)) => {
+                    insert_node_at_path(nodes, std::slice::from_ref(trash), node);
+                    info!("Moved to {}: {:?}", trash, path);
+                }
+                (Some(_), None) => info!("Removed from feather: {:?}", path),
+                (None, _) => {}
+            }
+            state.remove(joplin_id);
+        }
+        SyncAction::DeletedInFeather {
+            joplin_id,
+            is_folder,
+            path,
+        } => {
+            let item_type = if *is_folder { "folders" } else { "notes" };
+            joplin_struct.delete_item(item_type, joplin_id)?;
+            state.remove(joplin_id);
+            info!("Deleted in joplin: {:?}", path);
+        }
+        SyncAction::DeletedInBoth { joplin_id, .. } => {
+            state.remove(joplin_id);
+        }
+    }
+    Ok(())
+}
+
+// What the action will do, for the plan
+pub fn add_to_plan(action: &SyncAction, options: &SyncOptions, plan: &mut Plan) {
+    let kind = |is_folder: &bool| if *is_folder { "folder" } else { "note" };
+    match action {
+        SyncAction::Unchanged { .. } => {}
+        SyncAction::NewInJoplin {
+            is_folder, path, ..
+        } => plan.add("feather", &format!("add {}", kind(is_folder)), path, None),
+        SyncAction::NewInFeather { is_folder, path } => {
+            plan.add("joplin", &format!("add {}", kind(is_folder)), path, None)
+        }
+        SyncAction::ChangedInJoplin { path, .. } => plan.add("feather", "update note", path, None),
+        SyncAction::ChangedInFeather { path, .. } => plan.add("joplin", "update note", path, None),
+        SyncAction::ChangedInBoth { path, .. } => plan.add(
+            "both",
+            "resolve conflict",
+            path,
+            Some(format!("{:?}", options.conflict)),
+        ),
+        SyncAction::MovedInJoplin { from, to, .. } => {
+            plan.add("feather", "move", to, Some(format!("from {:?}", from)))
+        }
+        SyncAction::MovedInFeather { is_folder, to, .. } => {
+            plan.add("joplin", &format!("move {}", kind(is_folder)), to, None)
+        }
+        SyncAction::DeletedInJoplin { path, .. } => match &options.trash_node {
+            Some(trash) => plan.add("feather", "move to trash", path, Some(trash.clone())),
+            None => plan.add("feather", "remove", path, None),
+        },
+        SyncAction::DeletedInFeather {
+            is_folder, path, ..
+        } => plan.add("joplin", &format!("delete {}", kind(is_folder)), path, None),
+        SyncAction::DeletedInBoth { path, .. } => plan.add(
+            "state",
+            "forget",
+            path,
+            Some("deleted on both sides".into()),
+        ),
+    }
+}
+
+pub fn sync_joplin_and_feathernotes(
+    mut feather_file: FeatherStruct,
+    mut joplin_struct: JoplinData,
+    joplin_folders: Vec<FoldersArray>,
+    joplin_folder_id: Option<String>,
+    output_file: &str,
+    options: SyncOptions,
+    dry_run: bool,
+) -> Result<Plan, BridgeError> {
+    let state_path = SyncState::state_path(output_file);
+    let mut state = SyncState::load(&state_path)?;
+
+    let scope_id = joplin_folder_id.unwrap_or_default();
+    let scope: Vec<String> = if scope_id.is_empty() {
+        Vec::new()
+    } else {
+        joplin_struct
+            .get_path_folder(&scope_id)?
+            .into_iter()
+            .map(|x| x.title)
+            .collect()
+    };
+
+    info!("Comparing joplin and feather");
+    let actions = classify(
+        &feather_file,
+        &joplin_struct,
+        &joplin_folders,
+        &state,
+        &scope,
+        &[options.trash_node.as_deref(), options.tags_tree.as_deref()]
+            .into_iter()
+            .flatten()
+            .collect::<Vec<&str>>(),
+    )?;
+
+    let mut plan = Plan::new(dry_run);
+    for action in &actions {
+        add_to_plan(action, &options, &mut plan);
+    }
+    if let Some(root) = &options.tags_tree {
+        plan.add("feather", "rebuild tags", std::slice::from_ref(root), None);
+    }
+    plan.add("file", "write", &[], Some(output_file.to_string()));
+    plan.add("file", "write", &[], Some(state_path.clone()));
+
+    if dry_run {
+        return Ok(plan);
+    }
+
+    let mut summary = SyncSummary::default();
+    let mut applied = Ok(());
+    for action in &actions {
+        debug!("Sync action: {:?}", action);
+        applied = apply(
+            action,
+            &mut feather_file,
+            &mut joplin_struct,
+            &mut state,
+            &scope_id,
+            &options,
+            &mut summary,
+        );
+        if applied.is_err() {
+            break;
+        }
+        summary.count(action);
+    }
+    summary.log();
+
+    // Joplin already has the changes of the actions before, the next sync must know about
+    // them. The state only goes with the file it describes
+    if let Err(x) = applied {
+        warn!("Sync stopped, saving what was done so far");
+        let saved = feather_file
+            .write_file(output_file, options.backups)
+            .and_then(|_| state.save(&state_path));
+        if let Err(e) = saved {
+            warn!("Couldn't save the partial sync: {}", e);
+        }
+        return Err(x);
+    }
+
+    if let Some(root) = &options.tags_tree {
+        build_tags_tree(&mut feather_file, &joplin_struct, &joplin_folders, root)?;
+    }
+
+    feather_file.write_file(output_file, options.backups)?;
+    state.save(&state_path)?;
+    Ok(plan)
+}
@@ -0,0 +1,99 @@
+use crate::converter::new_converter;
+use crate::data_types::feather::*;
+use crate::data_types::joplin::*;
+use crate::error::BridgeError;
+use crate::todo::feather_name;
+use regex::Regex;
+
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TagMode {
+    None,
+    Line,      // "**Tags:** a, b" at the top of the body
+    Attribute, // FeatherNotes' own tag attribute of the node
+}
+
+// Joplin tags can have spaces, so they are kept apart by commas
+pub fn join_tags(tags: &[String]) -> String {
+    tags.join(", ")
+}
+
+pub fn add_tag_line(md: String, tags: &[String]) -> String {
+    if tags.is_empty() {
+        return md;
+    }
+    format!("**Tags:** {}\n\n{}", join_tags(tags), md)
+}
+
+// Tags only go from joplin to feather, the line is dropped on the way back
+pub fn take_tag_line(md: &str) -> String {
+    let re_tags = Regex::new(r"^\s*(?:\*\*)?Tags:(?:\*\*)?[^\n]*\n?(?:[ \t]*\n)*").unwrap();
+    re_tags.replace(md, "").to_string()
+}
+
+// With TagMode::Attribute the node tag is what joplin has, other modes leave it alone
+pub fn apply_node_tag(node: &mut Node, joplin_struct: &JoplinData, note_id: &str) {
+    if joplin_struct.tag_mode != TagMode::Attribute {
+        return;
+    }
+    let tags = joplin_struct.tags_of_note(note_id);
+    node.tag = if tags.is_empty() {
+        None
+    } else {
+        Some(join_tags(&tags))
+    };
+}
+
+// joplin://x-callback-url/openNote opens the note in the desktop app
+fn note_link(title: &str, path: &[String], note_id: &str) -> String {
+    let re_brackets = Regex::new(r"([\[\]])").unwrap();
+    let mut text = path.to_vec();
+    text.push(title.to_string());
+    format!(
+        "- [{}](joplin://x-callback-url/openNote?id={})",
+        re_brackets.replace_all(&text.join(" / "), r"\$1"),
+        note_id
+    )
+}
+
+// A root node with a node per tag, each listing the notes with that tag. It's made again every
+// run and never synced, only notes in joplin_folders are listed
+pub fn build_tags_tree(
+    feather_file: &mut FeatherStruct,
+    joplin_struct: &JoplinData,
+    joplin_folders: &[FoldersArray],
+    root_name: &str,
+) -> Result<(), BridgeError> {
+    let converter = new_converter(joplin_struct.converter, joplin_struct.math);
+    let mut root = Node::new(root_name, "");
+
+    for tag in &joplin_struct.tags {
+        let mut lines: Vec<String> = Vec::new();
+        for note in joplin_struct.notes_list.iter().filter(|x| {
+            joplin_folders.iter().any(|folder| folder.id == x.parent_id)
+                && joplin_struct
+                    .note_tags
+                    .get(&x.id)
+                    .map(|tags| tags.contains(&tag.title))
+                    .unwrap_or(false)
+        }) {
+            let path: Vec<String> = joplin_struct
+                .get_path_folder(&note.parent_id)?
+                .into_iter()
+                .map(|x| x.title)
+                .collect();
+            lines.push(note_link(&feather_name(note), &path, &note.id));
+        }
+        if lines.is_empty() {
+            continue;
+        }
+        debug!("Tag {} has {} notes", tag.title, lines.len());
+        let body = converter.md_to_html(lines.join("\n"))?;
+        root.node.push(Node::new(&tag.title, &body));
+    }
+
+    remove_node_at_path(&mut feather_file.struct_xml.node, &[root_name.to_string()]);
+    if !root.node.is_empty() {
+        feather_file.struct_xml.node.push(root);
+    }
+    Ok(())
+}
@@ -0,0 +1,87 @@
+use crate::data_types::joplin::NotesArray;
+use chrono::{Local, NaiveDate, NaiveDateTime, TimeZone};
+use regex::Regex;
+use serde_json::{json, Value};
+
+// Joplin to-dos have a box in front of their node name in feather
+const OPEN_BOX: &str = "☐ ";
+const DONE_BOX: &str = "☑ ";
+const DUE_FORMAT: &str = "%Y-%m-%d %H:%M";
+
+pub fn feather_name(note: &NotesArray) -> String {
+    if note.is_todo == 0 {
+        return note.title.clone();
+    }
+    if note.todo_completed == 0 {
+        format!("{}{}", OPEN_BOX, note.title)
+    } else {
+        format!("{}{}", DONE_BOX, note.title)
+    }
+}
+
+// "☑ Buy milk" is the completed to-do "Buy milk", without a box it's a normal note
+pub fn parse_feather_name(name: &str) -> (&str, Option<bool>) {
+    if let Some(title) = name.strip_prefix(OPEN_BOX) {
+        (title, Some(false))
+    } else if let Some(title) = name.strip_prefix(DONE_BOX) {
+        (title, Some(true))
+    } else {
+        (name, None)
+    }
+}
+
+// Title and to-do fields for joplin from a node name. A to-do that was done already keeps its time
+pub fn todo_fields(name: &str, existing: Option<&NotesArray>) -> Value {
+    let (title, todo) = parse_feather_name(name);
+    let completed = match (todo, existing) {
+        (Some(true), Some(note)) if note.todo_completed != 0 => note.todo_completed,
+        (Some(true), _) => Local::now().timestamp_millis(),
+        _ => 0,
+    };
+    json!({
+        "title": title,
+        "is_todo": if todo.is_some() { 1 } else { 0 },
+        "todo_completed": completed,
+    })
+}
+
+// The due date goes in front of the body, it's in local time like joplin shows it
+pub fn add_due_header(md: String, note: &NotesArray) -> String {
+    if note.is_todo == 0 || note.todo_due == 0 {
+        return md;
+    }
+    match Local.timestamp_millis_opt(note.todo_due).single() {
+        Some(due) => format!("**Due:** {}\n\n{}", due.format(DUE_FORMAT), md),
+        None => md,
+    }
+}
+
+// And back, the header is taken out of the body. No header means no due date
+pub fn take_due_header(md: &str) -> (String, i64) {
+    let re_due = Regex::new(
+        r"^\s*(?:\*\*)?Due:(?:\*\*)?:?\s*(\d{4}-\d{2}-\d{2})(?:[ \t]+(\d{1,2}:\d{2}))?[ \t]*\n?(?:[ \t]*\n)*",
+    )
+    .unwrap();
+    let cap = match re_due.captures(md) {
+        Some(x) => x,
+        None => return (md.to_string(), 0),
+    };
+
+    let date = NaiveDate::parse_from_str(&cap[1], "%Y-%m-%d").ok();
+    let time = cap.get(2).map(|x| x.as_str()).unwrap_or("00:00");
+    let due = date
+        .and_then(|x| NaiveDateTime::parse_from_str(&format!("{} {}", x, time), DUE_FORMAT).ok())
+        .and_then(|x| Local.from_local_datetime(&x).earliest())
+        .map(|x| x.timestamp_millis());
+
+    match due {
+        Some(due) => (md[cap[0].len()..].to_string(), due),
+        None => {
+            warn!(
+                "Couldn't read the due date {}, leaving it in the body",
+                &cap[0]
+            );
+            (md.to_string(), 0)
+        }
+    }
+}
@@ -0,0 +1,50 @@
+use crate::error::BridgeError;
+use std::path::{Path, PathBuf};
+
+// $XDG_CACHE_HOME/feather-joplin-bridge/<name>, or the same in ~/.cache. Without a home it's a
+// dir of the user in the temp dir, never one shared by everyone
+pub fn cache_dir(name: &str) -> PathBuf {
+    let base = match std::env::var("XDG_CACHE_HOME") {
+        Ok(x) if !x.is_empty() => PathBuf::from(x),
+        _ => match std::env::var("HOME") {
+            Ok(x) if !x.is_empty() => PathBuf::from(x).join(".cache"),
+            _ => match std::env::var("LOCALAPPDATA") {
+                Ok(x) => PathBuf::from(x),
+                Err(_) => {
+                    let user = std::env::var("USER")
+                        .or_else(|_| std::env::var("USERNAME"))
+                        .unwrap_or_else(|_| "user".to_string());
+                    std::env::temp_dir().join(format!("feather-joplin-bridge-{}", user))
+                }
+            },
+        },
+    };
+    base.join("feather-joplin-bridge").join(name)
+}
+
+// Only the user can get in, so nobody else can put a file there for the bridge to use
+pub fn create_private_dir(dir: &Path) -> Result<(), BridgeError> {
+    let mut builder = std::fs::DirBuilder::new();
+    builder.recursive(true);
+    #[cfg(unix)]
+    std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+    builder.create(dir)?;
+
+    #[cfg(unix)]
+    {
+        // mode() only applies to new dirs, and this fails on a dir of someone else
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(dir, std::fs::Permissions::from_mode(0o700))?;
+    }
+    Ok(())
+}
+
+// FNV-1a, stable between runs and rust versions unlike DefaultHasher
+pub fn hash_body(text: &str) -> String {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in text.as_bytes() {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    format!("{:016x}", hash)
+}
@@ -0,0 +1,465 @@
+// A stand-in for the Joplin Data API, seeded from tests/fixtures/joplin. Only what the bridge
+// uses is there: ping, paginated listings with fields, single items, resources, tags and the
+// write endpoints
+#![allow(dead_code)]
+
+use serde_json::{json, Map, Value};
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::Duration;
+use tiny_http::{Header, Method, Request, Response, Server};
+
+pub const TOKEN: &str = "mock-token";
+
+pub fn fixtures_dir() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests")
+        .join("fixtures")
+}
+
+// A directory of its own for every test, tests run at the same time
+pub fn test_dir(name: &str) -> PathBuf {
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join(name);
+    std::fs::remove_dir_all(&dir).ok();
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+// With BLESS set the actual output becomes the golden file. Otherwise, on a mismatch, it's
+// left in the target tmp dir so it can be looked at
+pub fn check_golden(name: &str, actual: &str) -> Result<(), String> {
+    let golden_path = fixtures_dir().join("golden").join(name);
+    if std::env::var_os("BLESS").is_some() {
+        std::fs::create_dir_all(golden_path.parent().unwrap()).unwrap();
+        std::fs::write(&golden_path, actual).unwrap();
+        return Ok(());
+    }
+    let golden = std::fs::read_to_string(&golden_path).unwrap_or_default();
+    if golden != actual {
+        let actual_path = Path::new(env!("CARGO_TARGET_TMPDIR")).join(name);
+        std::fs::create_dir_all(actual_path.parent().unwrap()).unwrap();
+        std::fs::write(&actual_path, actual).unwrap();
+        return Err(format!(
+            "{} differs from {}, the actual output is in {}",
+            name,
+            golden_path.display(),
+            actual_path.display()
+        ));
+    }
+    Ok(())
+}
+
+pub fn assert_golden(name: &str, actual: &str) {
+    if let Err(x) = check_golden(name, actual) {
+        panic!("{}", x);
+    }
+}
+
+#[derive(Default)]
+pub struct MockState {
+    pub folders: Vec<Value>,
+    pub notes: Vec<Value>,
+    pub tags: Vec<Value>, // With note_ids, which the API has at /tags/:id/notes
+    pub resources: Vec<Value>, // With note_ids, which the API has at /notes/:id/resources
+    pub resource_files: HashMap<String, Vec<u8>>,
+    pub page_size: usize,
+    pub clock: i64,                      // updated_time of the next write
+    pub log: Vec<String>,                // "POST /notes" of every request that changed something
+    pub requests: Vec<String>,           // "GET /folders/:id" of every request, without the query
+    pub slow: HashMap<String, Duration>, // Paths answered late, so answers overtake each other
+    pub broken: HashSet<String>,         // Paths answered with a 500, like joplin failing mid-run
+    next_id: u64,
+}
+
+impl MockState {
+    fn load(dir: &Path) -> MockState {
+        let read = |name: &str| -> Vec<Value> {
+            let text = std::fs::read_to_string(dir.join(name)).unwrap();
+            serde_json::from_str(&text).unwrap()
+        };
+        let mut state = MockState {
+            folders: read("folders.json"),
+            notes: read("notes.json"),
+            tags: read("tags.json"),
+            resources: read("resources.json"),
+            page_size: 100, // The biggest limit joplin takes
+            clock: 1_700_000_000_000,
+            ..Default::default()
+        };
+        for resource in &state.resources {
+            let id = resource["id"].as_str().unwrap().to_string();
+            let file = dir
+                .join("resources")
+                .join(resource["title"].as_str().unwrap());
+            state
+                .resource_files
+                .insert(id, std::fs::read(file).unwrap());
+        }
+        state
+    }
+
+    fn new_id(&mut self) -> String {
+        self.next_id += 1;
+        format!("{:032x}", 0xf000_0000 + self.next_id)
+    }
+
+    fn tick(&mut self) -> i64 {
+        self.clock += 1000;
+        self.clock
+    }
+
+    fn items(&mut self, item_type: &str) -> Option<&mut Vec<Value>> {
+        match item_type {
+            "folders" => Some(&mut self.folders),
+            "notes" => Some(&mut self.notes),
+            "tags" => Some(&mut self.tags),
+            "resources" => Some(&mut self.resources),
+            _ => None,
+        }
+    }
+
+    pub fn folder_by_title(&self, title: &str) -> Option<&Value> {
+        self.folders.iter().find(|x| x["title"] == title)
+    }
+
+    pub fn note_by_title(&self, title: &str) -> Option<&Value> {
+        self.notes.iter().find(|x| x["title"] == title)
+    }
+
+    // A change made in joplin itself between two runs, updated_time moves like with a PUT
+    pub fn edit_note(&mut self, title: &str, changes: Value) {
+        let time = self.tick();
+        let note = self.notes.iter_mut().find(|x| x["title"] == title).unwrap();
+        for (key, value) in changes.as_object().unwrap() {
+            note[key] = value.clone();
+        }
+        note["updated_time"] = json!(time);
+    }
+}
+
+pub struct MockJoplin {
+    pub url: String,
+    pub state: Arc<Mutex<MockState>>,
+    server: Arc<Server>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl MockJoplin {
+    pub fn start() -> MockJoplin {
+        MockJoplin::start_with(fixtures_dir().join("joplin"), 100)
+    }
+
+    // A small page size makes the bridge go through pages with few fixtures
+    pub fn start_with(dir: PathBuf, page_size: usize) -> MockJoplin {
+        // Downloads go to the target dir, not to the cache of whoever runs the tests
+        std::env::set_var(
+            "XDG_CACHE_HOME",
+            Path::new(env!("CARGO_TARGET_TMPDIR")).join("cache"),
+        );
+        let mut state = MockState::load(&dir);
+        state.page_size = page_size;
+        let state = Arc::new(Mutex::new(state));
+
+        let server = Arc::new(Server::http("127.0.0.1:0").unwrap());
+        let port = server.server_addr().to_ip().unwrap().port();
+
+        let thread_server = server.clone();
+        let thread_state = state.clone();
+        let thread = std::thread::spawn(move || {
+            for request in thread_server.incoming_requests() {
+                let state = thread_state.clone();
+                std::thread::spawn(move || handle(request, &state));
+            }
+        });
+
+        MockJoplin {
+            url: format!("http://127.0.0.1:{}", port),
+            state,
+            server,
+            thread: Some(thread),
+        }
+    }
+}
+
+impl Drop for MockJoplin {
+    fn drop(&mut self) {
+        self.server.unblock();
+        if let Some(thread) = self.thread.take() {
+            thread.join().ok();
+        }
+    }
+}
+
+fn percent_decode(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut out = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' if i + 2 < bytes.len() => {
+                let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).unwrap_or("");
+                match u8::from_str_radix(hex, 16) {
+                    Ok(x) => {
+                        out.push(x);
+                        i += 3;
+                        continue;
+                    }
+                    Err(_) => out.push(b'%'),
+                }
+            }
+            b'+' => out.push(b' '),
+            x => out.push(x),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).to_string()
+}
+
+fn parse_query(query: &str) -> HashMap<String, String> {
+    query
+        .split('&')
+        .filter(|x| !x.is_empty())
+        .map(|x| match x.split_once('=') {
+            Some((k, v)) => (percent_decode(k), percent_decode(v)),
+            None => (percent_decode(x), String::new()),
+        })
+        .collect()
+}
+
+fn json_response(status: u16, value: &Value) -> Response<std::io::Cursor<Vec<u8>>> {
+    Response::from_string(value.to_string())
+        .with_status_code(status)
+        .with_header(Header::from_bytes("Content-Type", "application/json").unwrap())
+}
+
+fn error_response(status: u16, message: &str) -> Response<std::io::Cursor<Vec<u8>>> {
+    json_response(status, &json!({ "error": message }))
+}
+
+// Like Joplin, listings without fields have only id, parent_id and title
+fn pick_fields(item: &Value, fields: Option<&String>, default: &[&str]) -> Value {
+    let wanted: Vec<&str> = match fields {
+        Some(x) => x.split(',').collect(),
+        None if default.is_empty() => return without_mock_fields(item),
+        None => default.to_vec(),
+    };
+    let mut picked = Map::new();
+    for field in wanted {
+        if let Some(x) = item.get(field) {
+            picked.insert(field.to_string(), x.clone());
+        }
+    }
+    Value::Object(picked)
+}
+
+// note_ids only tell the mock what belongs together
+fn without_mock_fields(item: &Value) -> Value {
+    let mut item = item.clone();
+    if let Some(x) = item.as_object_mut() {
+        x.remove("note_ids");
+    }
+    item
+}
+
+// Numbers and strings, like the columns joplin sorts by
+fn sort_items(items: &mut [Value], query: &HashMap<String, String>) {
+    if let Some(field) = query.get("order_by") {
+        items.sort_by(|a, b| match (a[field].as_str(), b[field].as_str()) {
+            (Some(a), Some(b)) => a.cmp(b),
+            _ => a[field].as_i64().cmp(&b[field].as_i64()),
+        });
+        if query.get("order_dir").map(|x| x.as_str()) == Some("DESC") {
+            items.reverse();
+        }
+    }
+}
+
+fn page(items: Vec<Value>, query: &HashMap<String, String>, page_size: usize) -> Value {
+    let page: usize = query.get("page").and_then(|x| x.parse().ok()).unwrap_or(1);
+    let limit = query
+        .get("limit")
+        .and_then(|x| x.parse().ok())
+        .unwrap_or(10)
+        .min(page_size.max(1));
+    let start = (page.max(1) - 1) * limit;
+    let listed: Vec<Value> = items.iter().skip(start).take(limit).cloned().collect();
+    json!({ "items": listed, "has_more": start + limit < items.len() })
+}
+
+fn handle(mut request: Request, state: &Arc<Mutex<MockState>>) {
+    let url = request.url().to_string();
+    let (path, query) = url.split_once('?').unwrap_or((&url, ""));
+    let query = parse_query(query);
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+    let method = request.method().clone();
+
+    let mut body = Vec::new();
+    request.as_reader().read_to_end(&mut body).ok();
+
+    let (delay, broken) = {
+        let mut state = state.lock().unwrap();
+        state.requests.push(format!("{} {}", method, path));
+        (state.slow.get(path).copied(), state.broken.contains(path))
+    };
+    if let Some(delay) = delay {
+        std::thread::sleep(delay);
+    }
+
+    let response = if broken {
+        error_response(500, "Internal Server Error")
+    } else if segments == ["ping"] {
+        Response::from_string("JoplinClipperServer")
+    } else if query.get("token").map(|x| x.as_str()) != Some(TOKEN) {
+        error_response(403, "Invalid \"token\" parameter")
+    } else {
+        let mut state = state.lock().unwrap();
+        route(&mut state, &method, &segments, &query, &body)
+    };
+    request.respond(response).ok();
+}
+
+fn route(
+    state: &mut MockState,
+    method: &Method,
+    segments: &[&str],
+    query: &HashMap<String, String>,
+    body: &[u8],
+) -> Response<std::io::Cursor<Vec<u8>>> {
+    let fields = query.get("fields");
+    let page_size = state.page_size;
+    let list_default: &[&str] = &["id", "parent_id", "title"];
+
+    match (method, segments) {
+        (Method::Get, [_]) | (Method::Get, [_, _, _])
+            if query
+                .get("limit")
+                .and_then(|x| x.parse::<usize>().ok())
+                .is_some_and(|x| x > 100) =>
+        {
+            error_response(400, "Limit cannot be over 100")
+        }
+        (Method::Get, [item_type]) => match state.items(item_type) {
+            Some(items) => {
+                // Sorted before picking the fields, order_by needn't be one of them
+                let mut items = items.clone();
+                sort_items(&mut items, query);
+                let default: &[&str] = if *item_type == "tags" || *item_type == "resources" {
+                    &["id", "title"]
+                } else {
+                    list_default
+                };
+                let listed = items
+                    .iter()
+                    .map(|x| pick_fields(x, fields, default))
+                    .collect();
+                json_response(200, &page(listed, query, page_size))
+            }
+            None => error_response(404, "Not Found"),
+        },
+        (Method::Get, ["resources", id, "file"]) => match state.resource_files.get(*id) {
+            Some(bytes) => Response::from_data(bytes.clone()),
+            None => error_response(404, "Not Found"),
+        },
+        (Method::Get, ["notes", id, "resources"]) => {
+            let listed = state
+                .resources
+                .iter()
+                .filter(|x| x["note_ids"].as_array().unwrap().iter().any(|n| n == id))
+                .map(|x| pick_fields(x, fields, &["id", "title"]))
+                .collect();
+            json_response(200, &page(listed, query, page_size))
+        }
+        (Method::Get, ["tags", id, "notes"]) => {
+            let note_ids: Vec<Value> = state
+                .tags
+                .iter()
+                .find(|x| x["id"] == *id)
+                .and_then(|x| x["note_ids"].as_array().cloned())
+                .unwrap_or_default();
+            let listed = state
+                .notes
+                .iter()
+                .filter(|x| note_ids.contains(&x["id"]))
+                .map(|x| pick_fields(x, fields, list_default))
+                .collect();
+            json_response(200, &page(listed, query, page_size))
+        }
+        (Method::Get, [item_type, id]) => match state
+            .items(item_type)
+            .and_then(|items| items.iter().find(|x| x["id"] == *id))
+        {
+            Some(item) => json_response(200, &pick_fields(item, fields, &[])),
+            None => error_response(404, "Not Found"),
+        },
+        (Method::Post, ["resources"]) => {
+            // The multipart body is kept as the file, only the bridge's title is read from it
+            let text = String::from_utf8_lossy(body).to_string();
+            let title = text
+                .split("\"title\":\"")
+                .nth(1)
+                .and_then(|x| x.split('"').next())
+                .unwrap_or("upload")
+                .to_string();
+            let id = state.new_id();
+            state.log.push(format!("POST /resources {}", title));
+            state
+                .resources
+                .push(json!({ "id": id, "title": title, "note_ids": [] }));
+            state.resource_files.insert(id.clone(), body.to_vec());
+            json_response(200, &json!({ "id": id, "title": title }))
+        }
+        (Method::Post, [item_type @ ("folders" | "notes")]) => {
+            let mut item: Value = match serde_json::from_slice(body) {
+                Ok(x) => x,
+                Err(_) => return error_response(400, "Bad json"),
+            };
+            let id = state.new_id();
+            let time = state.tick();
+            item["id"] = json!(id);
+            item["updated_time"] = json!(time);
+            if item.get("parent_id").is_none() {
+                item["parent_id"] = json!("");
+            }
+            state.log.push(format!(
+                "POST /{} {}",
+                item_type,
+                item["title"].as_str().unwrap_or_default()
+            ));
+            state.items(item_type).unwrap().push(item.clone());
+            json_response(200, &item)
+        }
+        (Method::Put, [item_type @ ("folders" | "notes"), id]) => {
+            let changes: Value = match serde_json::from_slice(body) {
+                Ok(x) => x,
+                Err(_) => return error_response(400, "Bad json"),
+            };
+            let time = state.tick();
+            state.log.push(format!("PUT /{}/{}", item_type, id));
+            let items = state.items(item_type).unwrap();
+            match items.iter_mut().find(|x| x["id"] == *id) {
+                Some(item) => {
+                    for (key, value) in changes.as_object().into_iter().flatten() {
+                        item[key] = value.clone();
+                    }
+                    item["updated_time"] = json!(time);
+                    json_response(200, item)
+                }
+                None => error_response(404, "Not Found"),
+            }
+        }
+        (Method::Delete, [item_type @ ("folders" | "notes"), id]) => {
+            state.log.push(format!("DELETE /{}/{}", item_type, id));
+            let items = state.items(item_type).unwrap();
+            let before = items.len();
+            items.retain(|x| x["id"] != *id);
+            if items.len() == before {
+                error_response(404, "Not Found")
+            } else {
+                Response::from_string("")
+            }
+        }
+        _ => error_response(404, "Not Found"),
+    }
+}
@@ -0,0 +1,35 @@
+mod common;
+
+use common::{check_golden, fixtures_dir};
+use feather_joplin_bridge::converter::{new_converter, ConverterKind};
+use feather_joplin_bridge::math::MathMode;
+
+// Every note in tests/fixtures/corpus goes to FeatherNotes html and back to markdown, both are
+// compared with tests/fixtures/golden/corpus. Run with BLESS=1 after changing the converter on
+// purpose, then look at the diff of the golden files
+#[test]
+fn corpus_matches_golden() {
+    let converter = new_converter(ConverterKind::Native, MathMode::Tex);
+    let mut notes: Vec<_> = std::fs::read_dir(fixtures_dir().join("corpus"))
+        .unwrap()
+        .map(|x| x.unwrap().path())
+        .filter(|x| x.extension().is_some_and(|x| x == "md"))
+        .collect();
+    notes.sort();
+    assert!(!notes.is_empty(), "The corpus is empty");
+
+    let mut failures = Vec::new();
+    for note in notes {
+        let name = note.file_stem().unwrap().to_string_lossy().to_string();
+        let md = std::fs::read_to_string(&note).unwrap();
+        let html = converter.md_to_html(md).unwrap();
+        let round_trip = converter.html_to_md(html.clone()).unwrap();
+
+        for (golden, actual) in [("html", &html), ("md", &round_trip)] {
+            if let Err(x) = check_golden(&format!("corpus/{}.{}", name, golden), actual) {
+                failures.push(x);
+            }
+        }
+    }
+    assert!(failures.is_empty(), "\n{}", failures.join("\n"));
+}
@@ -0,0 +1,475 @@
+mod common;
+
+use common::{assert_golden, fixtures_dir, test_dir, MockJoplin, TOKEN};
+use feather_joplin_bridge::attachments::{AttachmentMode, AttachmentOptions};
+use feather_joplin_bridge::auth;
+use feather_joplin_bridge::convert_logic::{
+    overwrite_feathernotes_to_joplin, overwrite_joplin_to_feathernotes,
+};
+use feather_joplin_bridge::data_types::feather::{FeatherStruct, Node};
+use feather_joplin_bridge::data_types::joplin::JoplinData;
+use feather_joplin_bridge::error::BridgeError;
+use feather_joplin_bridge::tags::TagMode;
+use serde_json::json;
+use std::path::Path;
+use std::time::Duration;
+
+// What main does before converting, attachments go next to the output
+fn connect(mock: &MockJoplin, output: &str) -> JoplinData {
+    let mut joplin = JoplinData::new(TOKEN.to_string(), &mock.url).unwrap();
+    joplin.attachments = AttachmentOptions::new(output, AttachmentMode::Export, 1024 * 1024);
+    joplin
+}
+
+fn overwrite_feather(
+    mock: &MockJoplin,
+    test: &str,
+    golden: &str,
+    jobs: usize,
+    tags: TagMode,
+    tags_tree: Option<&str>,
+) {
+    let dir = test_dir(test);
+    let output = dir.join("notes.fnx").to_string_lossy().to_string();
+    let mut joplin = connect(mock, &output);
+    joplin.jobs = jobs;
+    joplin.tag_mode = tags;
+    if tags != TagMode::None || tags_tree.is_some() {
+        joplin.load_tags().unwrap();
+    }
+    joplin.load_note_bodies().unwrap();
+    let folders = joplin.dir_list.clone();
+
+    overwrite_joplin_to_feathernotes(
+        FeatherStruct::new(),
+        joplin,
+        folders,
+        &output,
+        tags_tree.map(|x| x.to_string()),
+        0,
+        false,
+    )
+    .unwrap();
+
+    assert_golden(golden, &std::fs::read_to_string(&output).unwrap());
+    let attachment = dir
+        .join("notes_attachments")
+        .join(format!("{:032x}.txt", 0xe2));
+    assert_eq!(std::fs::read(attachment).unwrap(), b"Specification\n");
+}
+
+#[test]
+fn overwrite_feather_matches_golden() {
+    let mock = MockJoplin::start();
+    overwrite_feather(
+        &mock,
+        "overwrite_feather",
+        "overwrite_feather.fnx",
+        1,
+        TagMode::None,
+        None,
+    );
+}
+
+#[test]
+fn overwrite_feather_with_tags_matches_golden() {
+    let mock = MockJoplin::start();
+    overwrite_feather(
+        &mock,
+        "overwrite_feather_tags",
+        "overwrite_feather_tags.fnx",
+        1,
+        TagMode::Attribute,
+        Some("Tags"),
+    );
+}
+
+// Every listing takes a few pages, the result must not change
+#[test]
+fn small_pages_give_the_same_file() {
+    let mock = MockJoplin::start_with(fixtures_dir().join("joplin"), 2);
+    overwrite_feather(
+        &mock,
+        "small_pages",
+        "overwrite_feather.fnx",
+        1,
+        TagMode::None,
+        None,
+    );
+}
+
+// Workers finish in any order, the nodes must not
+#[test]
+fn many_jobs_give_the_same_file() {
+    let mock = MockJoplin::start();
+    let first = format!("/notes/{:032x}/resources", 0xb1);
+    mock.state
+        .lock()
+        .unwrap()
+        .slow
+        .insert(first, Duration::from_millis(500));
+    overwrite_feather(
+        &mock,
+        "many_jobs",
+        "overwrite_feather_tags.fnx",
+        8,
+        TagMode::Attribute,
+        Some("Tags"),
+    );
+}
+
+// Everything comes from a few listings, only notes linking resources need a request of their own
+#[test]
+fn overwrite_feather_needs_few_requests() {
+    let mock = MockJoplin::start();
+    overwrite_feather(
+        &mock,
+        "few_requests",
+        "overwrite_feather.fnx",
+        1,
+        TagMode::None,
+        None,
+    );
+
+    let state = mock.state.lock().unwrap();
+    let count = |wanted: &dyn Fn(&[&str]) -> bool| {
+        state
+            .requests
+            .iter()
+            .filter(|x| {
+                let segments: Vec<&str> = x.split(['/', ' ']).filter(|x| !x.is_empty()).collect();
+                wanted(&segments)
+            })
+            .count()
+    };
+    assert_eq!(
+        count(&|x| x == ["GET", "folders"]),
+        1,
+        "{:#?}",
+        state.requests
+    );
+    assert_eq!(
+        count(&|x| x == ["GET", "notes"]),
+        2,
+        "{:#?}",
+        state.requests
+    );
+    assert_eq!(count(&|x| x.len() == 3), 0, "{:#?}", state.requests);
+    assert_eq!(
+        count(&|x| x.len() == 4 && x[1] == "notes"),
+        1,
+        "{:#?}",
+        state.requests
+    );
+}
+
+// Without load_note_bodies every body is asked for on its own, it must be the same
+#[test]
+fn note_body_without_listing() {
+    let mock = MockJoplin::start();
+    let dir = test_dir("note_body_without_listing");
+    let output = dir.join("notes.fnx").to_string_lossy().to_string();
+    let mut joplin = connect(&mock, &output);
+    let ids: Vec<String> = joplin.notes_list.iter().map(|x| x.id.clone()).collect();
+
+    let single: Vec<String> = ids
+        .iter()
+        .map(|x| joplin.get_note_body(x).unwrap())
+        .collect();
+    joplin.load_note_bodies().unwrap();
+    let listed: Vec<String> = ids
+        .iter()
+        .map(|x| joplin.get_note_body(x).unwrap())
+        .collect();
+    assert_eq!(single, listed);
+}
+
+#[test]
+fn wrong_token_is_an_auth_error() {
+    let mock = MockJoplin::start();
+    match JoplinData::new("wrong".to_string(), &mock.url) {
+        Err(BridgeError::Auth(_)) => {}
+        Err(x) => panic!("Expected an auth error, got {}", x),
+        Ok(_) => panic!("Expected an auth error, the token was taken"),
+    }
+}
+
+#[test]
+fn no_joplin_is_a_network_error() {
+    let url = {
+        let mock = MockJoplin::start();
+        mock.url.clone()
+    };
+    match JoplinData::new(TOKEN.to_string(), &url) {
+        Err(x) => assert_eq!(x.exit_code(), 3, "{}", x),
+        Ok(_) => panic!("The mock is stopped, nothing should answer"),
+    }
+
+    // Every other request has the token in its url, main prints the error
+    let failed = reqwest::blocking::get(format!("{}/folders?token={}", url, TOKEN)).unwrap_err();
+    let x = BridgeError::from(failed);
+    assert_eq!(x.exit_code(), 3, "{}", x);
+    assert!(!x.to_string().contains(TOKEN), "{}", x);
+}
+
+#[test]
+fn overwrite_joplin_writes_every_node() {
+    let mock = MockJoplin::start();
+    let dir = test_dir("overwrite_joplin");
+    let output = dir.join("notes.fnx").to_string_lossy().to_string();
+    let joplin = connect(&mock, &output);
+    let feather = FeatherStruct::read(
+        fixtures_dir()
+            .join("..")
+            .join("..")
+            .join("Feather-Nodes-example.fnx")
+            .to_string_lossy()
+            .to_string(),
+        false,
+    )
+    .unwrap();
+
+    overwrite_feathernotes_to_joplin(feather, joplin, None, false).unwrap();
+
+    let state = mock.state.lock().unwrap();
+    let node_2 = state.folder_by_title("Node 2").unwrap();
+    let node_2_1 = state.folder_by_title("Node 2.1").unwrap();
+    assert_eq!(node_2["parent_id"], "");
+    assert_eq!(node_2_1["parent_id"], node_2["id"]);
+
+    let leaf = state.note_by_title("Node 2.1.1").unwrap();
+    assert_eq!(leaf["parent_id"], node_2_1["id"]);
+    assert!(leaf["body"].as_str().unwrap().contains("Node 2.1.1"));
+    // Text of a node with children gets a note of its own inside the folder
+    let folder_text = state
+        .notes
+        .iter()
+        .find(|x| x["title"] == "Node 2" && x["parent_id"] == node_2["id"]);
+    assert!(folder_text.is_some());
+}
+
+// Running it again finds everything already there
+#[test]
+fn overwrite_joplin_skips_duplicates() {
+    let mock = MockJoplin::start();
+    let dir = test_dir("overwrite_joplin_twice");
+    let output = dir.join("notes.fnx").to_string_lossy().to_string();
+    let path = fixtures_dir()
+        .join("..")
+        .join("..")
+        .join("Feather-Nodes-example.fnx")
+        .to_string_lossy()
+        .to_string();
+
+    let mut writes = Vec::new();
+    for _ in 0..2 {
+        let feather = FeatherStruct::read(path.clone(), false).unwrap();
+        overwrite_feathernotes_to_joplin(feather, connect(&mock, &output), None, false).unwrap();
+        writes.push(mock.state.lock().unwrap().log.len());
+    }
+
+    let state = mock.state.lock().unwrap();
+    assert_eq!(
+        writes[0],
+        writes[1],
+        "The second run wrote: {:?}",
+        &state.log[writes[0]..]
+    );
+    let leaves = state
+        .notes
+        .iter()
+        .filter(|x| x["title"] == "Node 2.1.1")
+        .count();
+    assert_eq!(leaves, 1);
+}
+
+// A link in a feather node can point anywhere, only the attachments dir is uploaded
+#[test]
+fn overwrite_joplin_uploads_only_the_export_dir() {
+    let mock = MockJoplin::start();
+    let dir = test_dir("upload_export_dir");
+    let output = dir.join("notes.fnx").to_string_lossy().to_string();
+    std::fs::create_dir_all(dir.join("notes_attachments")).unwrap();
+    std::fs::write(dir.join("notes_attachments").join("report 1.txt"), "Report").unwrap();
+    std::fs::write(dir.join("secret.txt"), "Secret").unwrap();
+    let outside = dir.join("secret.txt").to_string_lossy().to_string();
+
+    let mut feather = FeatherStruct::new();
+    feather.struct_xml.node.push(Node::new(
+        "Links",
+        &format!(
+            "<html><body><p><a href=\"notes_attachments/report%201.txt\">report</a> \
+             <a href=\"notes_attachments/../secret.txt\">up</a> \
+             <a href=\"{}\">absolute</a></p></body></html>",
+            outside
+        ),
+    ));
+    overwrite_feathernotes_to_joplin(feather, connect(&mock, &output), None, false).unwrap();
+
+    let state = mock.state.lock().unwrap();
+    let uploads: Vec<&String> = state
+        .log
+        .iter()
+        .filter(|x| x.starts_with("POST /resources"))
+        .collect();
+    assert_eq!(uploads, ["POST /resources report"]);
+    let body = state.note_by_title("Links").unwrap()["body"]
+        .as_str()
+        .unwrap()
+        .to_string();
+    assert!(body.contains("[report](:/"), "{}", body);
+    assert!(
+        body.contains("(notes_attachments/../secret.txt)"),
+        "{}",
+        body
+    );
+    assert!(body.contains(&format!("({})", outside)), "{}", body);
+}
+
+// A space in the .fnx name ends up in the attachment links, they must still work both ways
+#[test]
+fn attachment_links_survive_spaces() {
+    let mock = MockJoplin::start();
+    let dir = test_dir("attachment_spaces");
+    let output = dir.join("My Notes.fnx").to_string_lossy().to_string();
+    let joplin = connect(&mock, &output);
+    let folders = joplin.dir_list.clone();
+    overwrite_joplin_to_feathernotes(
+        FeatherStruct::new(),
+        joplin,
+        folders,
+        &output,
+        None,
+        0,
+        false,
+    )
+    .unwrap();
+
+    let spec = format!("{:032x}", 0xe2);
+    let written = std::fs::read_to_string(&output).unwrap();
+    assert!(
+        written.contains(&format!("My%20Notes_attachments/{}.txt", spec)),
+        "{}",
+        written
+    );
+    let exported = dir
+        .join("My Notes_attachments")
+        .join(format!("{}.txt", spec));
+    let contents = std::fs::read(&exported).unwrap();
+
+    // An export that doesn't match the resource anymore is written again
+    std::fs::write(&exported, "stale").unwrap();
+    let joplin = connect(&mock, &output);
+    let folders = joplin.dir_list.clone();
+    overwrite_joplin_to_feathernotes(
+        FeatherStruct::new(),
+        joplin,
+        folders,
+        &output,
+        None,
+        0,
+        false,
+    )
+    .unwrap();
+    assert_eq!(std::fs::read(&exported).unwrap(), contents);
+
+    // Back to joplin the links are the resources they came from again, nothing is uploaded.
+    // A new folder, so Plan is written and not skipped as already there
+    let feather = FeatherStruct::read(output.clone(), false).unwrap();
+    let mut joplin = connect(&mock, &output);
+    let imported = joplin.create_folder("Imported", "").unwrap();
+    let writes = mock.state.lock().unwrap().log.len();
+    overwrite_feathernotes_to_joplin(feather, joplin, Some(imported.clone()), false).unwrap();
+    let state = mock.state.lock().unwrap();
+    let writes = &state.log[writes..];
+    assert!(
+        writes.contains(&"POST /notes Plan".to_string()),
+        "{:?}",
+        writes
+    );
+    assert!(
+        !writes.iter().any(|x| x.starts_with("POST /resources")),
+        "{:?}",
+        writes
+    );
+    let resources = state.resources.len();
+    assert_eq!(resources, 2, "{:?}", state.resources);
+    let plan = state.notes.iter().rfind(|x| x["title"] == "Plan").unwrap();
+    assert_ne!(plan["parent_id"], format!("{:032x}", 0xa3));
+    let body = plan["body"].as_str().unwrap();
+    assert!(body.contains(&format!("[spec](:/{})", spec)), "{}", body);
+    assert!(
+        body.contains(&format!("![logo](:/{:032x})", 0xe1)),
+        "{}",
+        body
+    );
+}
+
+// A resource changed in joplin has a new name in the cache, the old version isn't used
+#[test]
+fn changed_resource_is_downloaded_again() {
+    let mock = MockJoplin::start();
+    let dir = test_dir("changed_resource");
+    let output = dir.join("notes.fnx").to_string_lossy().to_string();
+    let spec = format!("{:032x}", 0xe2);
+    let exported = dir.join("notes_attachments").join(format!("{}.txt", spec));
+    let export = || {
+        let joplin = connect(&mock, &output);
+        let folders = joplin.dir_list.clone();
+        overwrite_joplin_to_feathernotes(
+            FeatherStruct::new(),
+            joplin,
+            folders,
+            &output,
+            None,
+            0,
+            false,
+        )
+        .unwrap();
+    };
+    export();
+    assert_ne!(std::fs::read_to_string(&exported).unwrap(), "Changed spec");
+
+    {
+        let mut state = mock.state.lock().unwrap();
+        state
+            .resource_files
+            .insert(spec.clone(), b"Changed spec".to_vec());
+        let resource = state.resources.iter_mut().find(|x| x["id"] == spec);
+        resource.unwrap()["updated_time"] = json!(1_800_000_000_000i64);
+    }
+    export();
+    assert_eq!(std::fs::read_to_string(&exported).unwrap(), "Changed spec");
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let cache = Path::new(env!("CARGO_TARGET_TMPDIR"))
+            .join("cache")
+            .join("feather-joplin-bridge")
+            .join("resources");
+        let mode = std::fs::metadata(cache).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o700);
+    }
+}
+
+// Asking for a token changes Joplin and writes the cache, a dry run does neither
+#[test]
+fn dry_run_doesnt_ask_for_a_token() {
+    let mock = MockJoplin::start();
+    let config = test_dir("dry_run_token");
+    std::env::set_var("XDG_CONFIG_HOME", &config);
+
+    match auth::get_token(&mock.url, None, true) {
+        Err(BridgeError::Auth(_)) => {}
+        Err(x) => panic!("Expected an auth error, got {}", x),
+        Ok(_) => panic!("A dry run got a token without one being there"),
+    }
+    let state = mock.state.lock().unwrap();
+    assert!(
+        !state.requests.iter().any(|x| x.contains("/auth")),
+        "{:?}",
+        state.requests
+    );
+    assert!(!config.join("feather-joplin-bridge").exists());
+}
@@ -0,0 +1,459 @@
+mod common;
+
+use chrono::Local;
+use common::{test_dir, MockJoplin, TOKEN};
+use feather_joplin_bridge::attachments::{AttachmentMode, AttachmentOptions};
+use feather_joplin_bridge::converter::{new_converter, ConverterKind};
+use feather_joplin_bridge::data_types::feather::{
+    insert_node_at_path, node_at_path, node_at_path_mut, remove_node_at_path, FeatherStruct, Node,
+};
+use feather_joplin_bridge::data_types::joplin::JoplinData;
+use feather_joplin_bridge::error::BridgeError;
+use feather_joplin_bridge::math::MathMode;
+use feather_joplin_bridge::plan::Plan;
+use feather_joplin_bridge::sync::{
+    sync_joplin_and_feathernotes, ConflictStrategy, SyncOptions, SyncState,
+};
+use serde_json::json;
+use std::path::Path;
+
+// One --sync run like main does it, the feather file is read again every time
+fn try_sync(mock: &MockJoplin, output: &str, options: SyncOptions) -> Result<Plan, BridgeError> {
+    let mut joplin = JoplinData::new(TOKEN.to_string(), &mock.url).unwrap();
+    joplin.attachments = AttachmentOptions::new(output, AttachmentMode::Export, 1024 * 1024);
+    let folders = joplin.dir_list.clone();
+    let feather = if Path::new(output).exists() {
+        FeatherStruct::read(output.to_string(), false).unwrap()
+    } else {
+        FeatherStruct::new()
+    };
+    sync_joplin_and_feathernotes(feather, joplin, folders, None, output, options, false)
+}
+
+fn sync(mock: &MockJoplin, output: &str, options: SyncOptions) -> Plan {
+    try_sync(mock, output, options).unwrap()
+}
+
+fn path(text: &str) -> Vec<String> {
+    text.split('/').map(|x| x.to_string()).collect()
+}
+
+// A change made in FeatherNotes between two runs
+fn change_feather(output: &str, change: impl FnOnce(&mut Vec<Node>)) {
+    let mut feather = FeatherStruct::read(output.to_string(), false).unwrap();
+    change(&mut feather.struct_xml.node);
+    feather.write_file(output, 0).unwrap();
+}
+
+fn edit_feather(output: &str, node_path: &str, md: &str) {
+    let html = new_converter(ConverterKind::Native, MathMode::Tex)
+        .md_to_html(md.to_string())
+        .unwrap();
+    change_feather(output, |nodes| {
+        node_at_path_mut(nodes, &path(node_path)).unwrap().text = html.into();
+    });
+}
+
+// Body of the node in the file, None if there is no node
+fn feather_text(output: &str, node_path: &str) -> Option<String> {
+    let feather = FeatherStruct::read(output.to_string(), false).unwrap();
+    node_at_path(&feather.struct_xml.node, &path(node_path))
+        .map(|x| x.text.as_str().unwrap().to_string())
+}
+
+fn joplin_body(mock: &MockJoplin, title: &str) -> String {
+    let state = mock.state.lock().unwrap();
+    state.note_by_title(title).unwrap()["body"]
+        .as_str()
+        .unwrap()
+        .to_string()
+}
+
+fn mark(mock: &MockJoplin) -> usize {
+    mock.state.lock().unwrap().log.len()
+}
+
+// What joplin was asked to change since the mark
+fn writes_since(mock: &MockJoplin, mark: usize) -> Vec<String> {
+    mock.state.lock().unwrap().log[mark..].to_vec()
+}
+
+fn id(n: u32) -> String {
+    format!("{:032x}", n)
+}
+
+fn options() -> SyncOptions {
+    SyncOptions {
+        conflict: ConflictStrategy::PreferJoplin,
+        feather_modified_time: 0,
+        trash_node: None,
+        tags_tree: None,
+        backups: 0,
+    }
+}
+
+// "joplin update note Work/Ideas" for every step, without the file writes every run has
+fn steps(plan: &Plan) -> Vec<String> {
+    plan.steps
+        .iter()
+        .filter(|x| x.target != "file")
+        .map(|x| format!("{} {} {}", x.target, x.action, x.path.join("/")))
+        .collect()
+}
+
+// Pulled bodies end with a newline the file doesn't keep, that isn't a change in feather
+#[test]
+fn second_sync_writes_nothing() {
+    let mock = MockJoplin::start();
+    let dir = test_dir("sync_twice");
+    let output = dir.join("notes.fnx").to_string_lossy().to_string();
+
+    sync(&mock, &output, options());
+    let written = std::fs::read_to_string(&output).unwrap();
+    let writes = mock.state.lock().unwrap().log.len();
+
+    let plan = sync(&mock, &output, options());
+    assert!(steps(&plan).is_empty(), "{:#?}", steps(&plan));
+    let state = mock.state.lock().unwrap();
+    assert_eq!(
+        state.log.len(),
+        writes,
+        "The second run wrote: {:?}",
+        &state.log[writes..]
+    );
+    assert_eq!(std::fs::read_to_string(&output).unwrap(), written);
+}
+
+// Joplin fails halfway, what was pushed before is in the state and isn't pushed again
+#[test]
+fn failed_sync_keeps_what_was_done() {
+    let mock = MockJoplin::start();
+    let dir = test_dir("sync_partial");
+    let output = dir.join("notes.fnx").to_string_lossy().to_string();
+    sync(&mock, &output, options());
+
+    edit_feather(&output, "Work/Ideas", "Ideas from feather");
+    edit_feather(&output, "Work/Projects/Plan", "Plan from feather");
+    let plan_path = format!("/notes/{}", id(0xb1));
+    mock.state.lock().unwrap().broken.insert(plan_path.clone());
+    let before = mark(&mock);
+    assert!(try_sync(&mock, &output, options()).is_err());
+    assert_eq!(
+        writes_since(&mock, before),
+        [format!("PUT /notes/{}", id(0xb2))]
+    );
+
+    mock.state.lock().unwrap().broken.clear();
+    let before = mark(&mock);
+    let plan = sync(&mock, &output, options());
+    assert_eq!(writes_since(&mock, before), [format!("PUT {}", plan_path)]);
+    assert_eq!(steps(&plan), ["joplin update note Work/Projects/Plan"]);
+    assert!(joplin_body(&mock, "Plan").contains("Plan from feather"));
+}
+
+// A synced copy of the mock notebook, ready for changes on either side
+fn synced(test: &str) -> (MockJoplin, String) {
+    let mock = MockJoplin::start();
+    let output = test_dir(test)
+        .join("notes.fnx")
+        .to_string_lossy()
+        .to_string();
+    sync(&mock, &output, options());
+    (mock, output)
+}
+
+#[test]
+fn note_changed_in_joplin_is_pulled() {
+    let (mock, output) = synced("sync_changed_in_joplin");
+    mock.state
+        .lock()
+        .unwrap()
+        .edit_note("Ideas", json!({ "body": "Ideas from joplin" }));
+
+    let before = mark(&mock);
+    let plan = sync(&mock, &output, options());
+    assert_eq!(steps(&plan), ["feather update note Work/Ideas"]);
+    assert!(writes_since(&mock, before).is_empty());
+    assert!(feather_text(&output, "Work/Ideas")
+        .unwrap()
+        .contains("Ideas from joplin"));
+}
+
+#[test]
+fn note_changed_in_feather_is_pushed() {
+    let (mock, output) = synced("sync_changed_in_feather");
+    edit_feather(&output, "Work/Ideas", "Ideas from feather");
+
+    let before = mark(&mock);
+    let plan = sync(&mock, &output, options());
+    assert_eq!(steps(&plan), ["joplin update note Work/Ideas"]);
+    assert_eq!(
+        writes_since(&mock, before),
+        [format!("PUT /notes/{}", id(0xb2))]
+    );
+    assert!(joplin_body(&mock, "Ideas").contains("Ideas from feather"));
+}
+
+// Ideas changed on both sides, synced with the strategy
+fn conflict(
+    test: &str,
+    conflict: ConflictStrategy,
+    feather_modified_time: i64,
+) -> (MockJoplin, String) {
+    let (mock, output) = synced(test);
+    mock.state
+        .lock()
+        .unwrap()
+        .edit_note("Ideas", json!({ "body": "Ideas from joplin" }));
+    edit_feather(&output, "Work/Ideas", "Ideas from feather");
+
+    let options = SyncOptions {
+        conflict,
+        feather_modified_time,
+        ..options()
+    };
+    let plan = sync(&mock, &output, options);
+    assert_eq!(steps(&plan), ["both resolve conflict Work/Ideas"]);
+    (mock, output)
+}
+
+fn assert_ideas(mock: &MockJoplin, output: &str, from: &str) {
+    let wanted = format!("Ideas from {}", from);
+    assert!(joplin_body(mock, "Ideas").contains(&wanted));
+    assert!(feather_text(output, "Work/Ideas")
+        .unwrap()
+        .contains(&wanted));
+}
+
+#[test]
+fn conflict_prefer_joplin() {
+    let (mock, output) = conflict("sync_prefer_joplin", ConflictStrategy::PreferJoplin, 0);
+    assert_ideas(&mock, &output, "joplin");
+}
+
+#[test]
+fn conflict_prefer_feather() {
+    let (mock, output) = conflict("sync_prefer_feather", ConflictStrategy::PreferFeather, 0);
+    assert_ideas(&mock, &output, "feather");
+}
+
+// The mock clock is in 2023, the feather file is either older or newer than the edit
+#[test]
+fn conflict_newest_wins() {
+    let (mock, output) = conflict("sync_newest_joplin", ConflictStrategy::NewestWins, 0);
+    assert_ideas(&mock, &output, "joplin");
+    let (mock, output) = conflict(
+        "sync_newest_feather",
+        ConflictStrategy::NewestWins,
+        i64::MAX,
+    );
+    assert_ideas(&mock, &output, "feather");
+}
+
+#[test]
+fn conflict_keep_both() {
+    let (mock, output) = conflict("sync_keep_both", ConflictStrategy::KeepBoth, 0);
+    assert_ideas(&mock, &output, "joplin");
+
+    let title = format!("Ideas (conflict {})", Local::now().format("%Y-%m-%d"));
+    assert!(joplin_body(&mock, &title).contains("Ideas from feather"));
+    assert_eq!(
+        mock.state.lock().unwrap().note_by_title(&title).unwrap()["parent_id"],
+        id(0xa1)
+    );
+    let conflict_path = format!("Work/{}", title);
+    assert!(feather_text(&output, &conflict_path)
+        .unwrap()
+        .contains("Ideas from feather"));
+
+    // Both copies are synced now
+    let plan = sync(&mock, &output, options());
+    assert!(steps(&plan).is_empty(), "{:#?}", steps(&plan));
+}
+
+#[test]
+fn rename_in_joplin() {
+    let (mock, output) = synced("sync_rename_joplin");
+    mock.state
+        .lock()
+        .unwrap()
+        .edit_note("Books", json!({ "title": "Reading" }));
+
+    let before = mark(&mock);
+    sync(&mock, &output, options());
+    assert!(writes_since(&mock, before).is_empty());
+    assert!(feather_text(&output, "Personal/Books").is_none());
+    assert!(feather_text(&output, "Personal/Reading")
+        .unwrap()
+        .contains("Dune"));
+}
+
+#[test]
+fn rename_in_feather() {
+    let (mock, output) = synced("sync_rename_feather");
+    change_feather(&output, |nodes| {
+        node_at_path_mut(nodes, &path("Personal/Books"))
+            .unwrap()
+            .name = "Reading".into();
+    });
+
+    let plan = sync(&mock, &output, options());
+    assert_eq!(steps(&plan), ["joplin move note Personal/Reading"]);
+    let state = mock.state.lock().unwrap();
+    let note = state.note_by_title("Reading").unwrap();
+    assert_eq!(note["id"], id(0xb6));
+    assert_eq!(note["parent_id"], id(0xa2));
+}
+
+#[test]
+fn move_in_joplin() {
+    let (mock, output) = synced("sync_move_joplin");
+    mock.state
+        .lock()
+        .unwrap()
+        .edit_note("Ideas", json!({ "parent_id": id(0xa2) }));
+
+    let before = mark(&mock);
+    sync(&mock, &output, options());
+    assert!(writes_since(&mock, before).is_empty());
+    assert!(feather_text(&output, "Work/Ideas").is_none());
+    assert!(feather_text(&output, "Personal/Ideas")
+        .unwrap()
+        .contains("Some ideas"));
+}
+
+#[test]
+fn move_in_feather() {
+    let (mock, output) = synced("sync_move_feather");
+    change_feather(&output, |nodes| {
+        let node = remove_node_at_path(nodes, &path("Work/Ideas")).unwrap();
+        insert_node_at_path(nodes, &path("Personal"), node);
+    });
+
+    let plan = sync(&mock, &output, options());
+    assert_eq!(steps(&plan), ["joplin move note Personal/Ideas"]);
+    let state = mock.state.lock().unwrap();
+    assert_eq!(state.note_by_title("Ideas").unwrap()["parent_id"], id(0xa2));
+}
+
+fn delete_in_joplin(mock: &MockJoplin, title: &str) {
+    mock.state
+        .lock()
+        .unwrap()
+        .notes
+        .retain(|x| x["title"] != title);
+}
+
+#[test]
+fn delete_in_joplin_removes_the_node() {
+    let (mock, output) = synced("sync_delete_joplin");
+    delete_in_joplin(&mock, "Films");
+
+    let plan = sync(&mock, &output, options());
+    assert_eq!(steps(&plan), ["feather remove Personal/Films"]);
+    assert!(feather_text(&output, "Personal/Films").is_none());
+    assert!(feather_text(&output, "Personal/Books").is_some());
+}
+
+fn with_trash() -> SyncOptions {
+    SyncOptions {
+        trash_node: Some("Trash".to_string()),
+        ..options()
+    }
+}
+
+#[test]
+fn delete_in_joplin_with_trash_node() {
+    let (mock, output) = synced("sync_trash_node");
+    delete_in_joplin(&mock, "Films");
+
+    sync(&mock, &output, with_trash());
+    assert!(feather_text(&output, "Personal/Films").is_none());
+    assert!(feather_text(&output, "Trash/Films")
+        .unwrap()
+        .contains("Nothing yet"));
+
+    // The trash is the bridge's, it isn't pushed to joplin
+    let before = mark(&mock);
+    let plan = sync(&mock, &output, with_trash());
+    assert!(steps(&plan).is_empty(), "{:#?}", steps(&plan));
+    assert!(writes_since(&mock, before).is_empty());
+}
+
+#[test]
+fn delete_in_feather_deletes_the_note() {
+    let (mock, output) = synced("sync_delete_feather");
+    change_feather(&output, |nodes| {
+        remove_node_at_path(nodes, &path("Personal/Films")).unwrap();
+    });
+
+    let before = mark(&mock);
+    let plan = sync(&mock, &output, options());
+    assert_eq!(steps(&plan), ["joplin delete note Personal/Films"]);
+    assert_eq!(
+        writes_since(&mock, before),
+        [format!("DELETE /notes/{}", id(0xb7))]
+    );
+    assert!(mock.state.lock().unwrap().note_by_title("Films").is_none());
+}
+
+// A folder made in feather has to be found again once it is renamed, not deleted and made again
+#[test]
+fn folder_new_in_feather_renamed() {
+    let (mock, output) = synced("sync_new_folder_feather");
+    change_feather(&output, |nodes| {
+        let mut trips = Node::new("Trips", "");
+        trips.node.push(Node::new("Paris", "<p>Eiffel tower</p>"));
+        insert_node_at_path(nodes, &path("Personal"), trips);
+    });
+    let plan = sync(&mock, &output, options());
+    assert_eq!(
+        steps(&plan),
+        [
+            "joplin add folder Personal/Trips",
+            "joplin add note Personal/Trips/Paris"
+        ]
+    );
+    let trips_id = mock.state.lock().unwrap().folder_by_title("Trips").unwrap()["id"]
+        .as_str()
+        .unwrap()
+        .to_string();
+    assert_eq!(feather_text(&output, "Personal/Trips").unwrap(), trips_id);
+
+    change_feather(&output, |nodes| {
+        node_at_path_mut(nodes, &path("Personal/Trips"))
+            .unwrap()
+            .name = "Travel".into();
+    });
+    let before = mark(&mock);
+    let plan = sync(&mock, &output, options());
+    assert_eq!(steps(&plan), ["joplin move folder Personal/Travel"]);
+    assert_eq!(
+        writes_since(&mock, before),
+        [format!("PUT /folders/{}", trips_id)]
+    );
+    let state = mock.state.lock().unwrap();
+    assert_eq!(state.folder_by_title("Travel").unwrap()["id"], trips_id);
+    let paris = state.note_by_title("Paris").unwrap();
+    assert_eq!(paris["parent_id"], trips_id);
+}
+
+// Without a state a node with the same name is only linked when it is the same note
+#[test]
+fn linking_a_different_note_is_a_conflict() {
+    let (mock, output) = synced("sync_link_conflict");
+    std::fs::remove_file(SyncState::state_path(&output)).unwrap();
+    edit_feather(&output, "Work/Ideas", "Ideas from feather");
+
+    let before = mark(&mock);
+    let options = SyncOptions {
+        conflict: ConflictStrategy::PreferFeather,
+        ..options()
+    };
+    sync(&mock, &output, options);
+    assert_eq!(
+        writes_since(&mock, before),
+        [format!("PUT /notes/{}", id(0xb2))]
+    );
+    assert_ideas(&mock, &output, "feather");
+}